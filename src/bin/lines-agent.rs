@@ -1,8 +1,6 @@
 #[macro_use]
 extern crate lazy_static;
-#[macro_use]
 extern crate log;
-#[macro_use]
 extern crate serde_derive;
 extern crate quicli;
 
@@ -10,7 +8,6 @@ extern crate cadence;
 extern crate hostname;
 extern crate lines;
 extern crate log4rs;
-extern crate rayon;
 extern crate regex;
 extern crate serde_humantime;
 extern crate serde_yaml;
@@ -19,21 +16,30 @@ extern crate statsd;
 // Good example for multiplatform code: https://github.com/luser/read-process-memory/blob/master/src/lib.rs
 
 use quicli::prelude::*;
-use std::time::{Duration, SystemTime};
-use rayon::ThreadPool;
+use std::time::{Duration, Instant};
 use std::thread;
-use std::net::UdpSocket;
-use cadence::{QueuingMetricSink, StatsdClient, UdpMetricSink};
-use lines::Sensor;
-use lines::sensors::{CpuTimeSensor, DiskSpaceSensor, PhysicalMemorySensor};
+use lines::Measurement;
+use lines::outputs::{self, Output, OutputConfig, OutputWorker, PrintFormat, PrintOutput};
+use lines::outputs::registry::{self as output_registry, OutputContext};
+use lines::runner::SensorRunner;
+use lines::schedule::{OverrunPolicy, Schedule};
+use lines::self_metrics::SelfMetricsSensor;
+use lines::sensors::SensorConfig;
+use lines::sensors::registry;
 use std::fs::File;
-use std::ffi::OsString;
-use std::path::PathBuf;
-use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, HashMap};
 use std::io::BufReader;
 use std::io::prelude::*;
 use regex::Regex;
 use std::env;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Sender};
+use serde_yaml::{Mapping, Value};
+use log::LevelFilter;
+use log4rs::append::console::{ConsoleAppender, Target};
+use log4rs::config::{Appender, Config as LoggingConfig, Root};
+use log4rs::encode::pattern::PatternEncoder;
 
 #[derive(Debug, StructOpt)]
 struct Arguments {
@@ -41,21 +47,62 @@ struct Arguments {
     config_directory: PathBuf,
     #[structopt(long = "output-directory", short = "o", parse(from_os_str))]
     output_directory: PathBuf,
+    /// Run every sensor a single time and exit, rather than running them on their intervals
+    #[structopt(long = "once")]
+    once: bool,
+    /// Print measurements to stdout instead of sending them to the configured outputs
+    #[structopt(long = "dry-run")]
+    dry_run: bool,
+    /// How --dry-run prints measurements, table or json
+    #[structopt(long = "format", default_value = "table")]
+    format: PrintFormat,
 }
 
 #[derive(Debug, PartialEq, Deserialize)]
 struct Config {
     hostname: String,
+    // Added as a tag to every measurement when set, e.g. production or staging
+    #[serde(default)]
+    environment: Option<String>,
+    // Extra tags added to every measurement
+    #[serde(default)]
+    tags: BTreeMap<String, String>,
     #[serde(with = "serde_humantime")]
     update_interval: Duration,
-    statsd_url: String,
+    // How long a sensor gets to produce its measurements before the tick goes on without them
+    #[serde(with = "serde_humantime", default = "default_sensor_timeout")]
+    sensor_timeout: Duration,
+    // What to do when collecting takes longer than a sensor's interval
+    #[serde(default)]
+    overrun_policy: OverrunPolicy,
+    // Where to send measurements, each one getting every measurement its filters let through
+    #[serde(default)]
+    outputs: Vec<OutputConfig>,
+    // Older configurations send to a single statsd server instead of listing outputs
+    #[serde(default)]
+    statsd_url: Option<String>,
+    #[serde(default = "default_statsd_port")]
     statsd_port: u16,
+    // Older configurations only list the disks to report free space for, and always report
+    // memory and CPU time as well
+    #[serde(default)]
     disks: Vec<String>,
+    #[serde(default)]
+    sensors: Vec<SensorConfig>,
 }
 
 static HOSTNAME_VARIABLE: &str = "hostname";
 static CONFIG_DIR_VARIABLE: &str = "config_directory";
 static OUTPUT_DIR_VARIABLE: &str = "output_directory";
+static ENVIRONMENT_TAG: &str = "environment";
+
+fn default_sensor_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_statsd_port() -> u16 {
+    8125
+}
 
 lazy_static! {
     // Variable syntax for config files is `${variable_name}`
@@ -63,12 +110,13 @@ lazy_static! {
 }
 
 fn main() {
-    println!("Starting up");
+    // Startup diagnostics go to stderr so that stdout only has what --dry-run prints
+    eprintln!("Starting up");
     for (index, arg) in env::args_os().enumerate() {
-        println!("Argument {}: {:?}", index, arg);
+        eprintln!("Argument {}: {:?}", index, arg);
     }
     let args = Arguments::from_args();
-    println!("Parsed arguments {:?}", args);
+    eprintln!("Parsed arguments {:?}", args);
     let config_directory = &args.config_directory;
     let output_directory = &args.output_directory;
     let config_directory_string = config_directory.to_string_lossy();
@@ -79,11 +127,11 @@ fn main() {
     let generated_logging_config = output_directory.join("generated_log4rs.yml");
     {
         let base_logging_config = config_directory.join("log4rs.yml");
-        println!(
+        eprintln!(
             "Looking for logging config source at {}",
             base_logging_config.display()
         );
-        println!(
+        eprintln!(
             "Writing generated logging config at {}",
             generated_logging_config.display()
         );
@@ -102,7 +150,11 @@ fn main() {
         thread::sleep(Duration::from_millis(1000));
     }
 
-    log4rs::init_file(generated_logging_config.clone(), Default::default()).unwrap();
+    if args.dry_run {
+        init_stderr_logging();
+    } else {
+        log4rs::init_file(generated_logging_config.clone(), Default::default()).unwrap();
+    }
     let config_file = File::open(config_directory.join("configuration.yml")).unwrap();
     let config: Config = serde_yaml::from_reader(config_file).expect("Error parsing configuration");
     let substituted_config = substitute_variables(config, &bindings);
@@ -110,33 +162,172 @@ fn main() {
     info!("Started up with arguments: {:?}", args);
     info!("Interpreted configuration: {:?}", substituted_config);
 
-    let exit_status = run(substituted_config);
+    let exit_status = run(substituted_config, &args);
     if let Err(e) = exit_status {
         error!("Exiting with error: {}", e);
         std::process::exit(1);
     }
 }
 
-fn run(config: Config) -> Result<()> {
-    let statsd_client =
-        make_statsd_client(&config.statsd_url, config.statsd_port, &config.hostname);
-    let update_interval = config.update_interval;
+// A dry run only logs to stderr, leaving stdout to the measurements and the log files to real runs
+fn init_stderr_logging() {
+    let stderr = ConsoleAppender::builder()
+        .target(Target::Stderr)
+        .encoder(Box::new(PatternEncoder::new("{d(%Y-%m-%d %H:%M:%S%.3f %Z)(utc)} (({T})) [{l}] {t}: {m}{n}")))
+        .build();
+    let logging_config = LoggingConfig::builder()
+        .appender(Appender::builder().build("stderr", Box::new(stderr)))
+        .build(Root::builder().appender("stderr").build(LevelFilter::Info))
+        .unwrap();
+    log4rs::init_config(logging_config).unwrap();
+}
+
+fn run(config: Config, args: &Arguments) -> Result<()> {
+    // A dry run doesn't need any outputs, or for their destinations to be reachable
+    let mut outputs = if args.dry_run { Vec::new() } else { build_outputs(&config, &args.output_directory)? };
+    let mut printer = if args.dry_run { Some(PrintOutput::stdout(args.format)) } else { None };
+
+    // Build every sensor before starting any, so a mistake in the configuration stops the agent
+    // up front rather than leaving it running with some sensors missing
+    let mut sensors: Vec<(SensorRunner, Duration)> = Vec::new();
+    for sensor_config in &sensor_configs(&config)? {
+        if !sensor_config.enabled {
+            info!("Sensor of type {} is disabled, skipping it", sensor_config.sensor_type);
+            continue;
+        }
+        let configured_sensor = registry::build_sensor(sensor_config)?;
+        let interval = sensor_config.interval.unwrap_or(config.update_interval);
+        sensors.push((SensorRunner::new(&configured_sensor.name, configured_sensor.sensor), interval));
+    }
+
+    let default_tags = create_default_tags(&config);
+    let mut deliver = |mut measurements: Vec<Measurement>| -> Result<()> {
+        for measurement in &mut measurements {
+            measurement.add_default_tags(&default_tags);
+        }
+        if let Some(ref mut printer) = printer {
+            return Ok(printer.send(&measurements)?);
+        }
+        let measurements = Arc::new(measurements);
+        for output in &mut outputs {
+            output.send(&measurements);
+        }
+        Ok(())
+    };
+
+    if args.once {
+        // Every sensor runs at the same time and gets the same time to finish as on a schedule
+        let deadline = Instant::now() + config.sensor_timeout;
+        for &mut (ref mut runner, _) in &mut sensors {
+            runner.start();
+        }
+        let mut measurements = Vec::new();
+        for &mut (ref mut runner, _) in &mut sensors {
+            measurements.extend(runner.finish(deadline));
+        }
+        deliver(measurements)?;
+        for output in outputs {
+            output.finish();
+        }
+        return Ok(());
+    }
+
+    sensors.push((SensorRunner::new("self_metrics", Box::new(SelfMetricsSensor)), config.update_interval));
+    let (measurement_sender, measurement_receiver) = mpsc::channel();
+    for (runner, interval) in sensors {
+        info!("Running sensor {} every {:?}", runner.name(), interval);
+        let schedule = Schedule::new(runner.name(), interval, config.overrun_policy);
+        spawn_sensor_loop(runner, schedule, config.sensor_timeout, measurement_sender.clone());
+    }
+    drop(measurement_sender);
+
+    for measurements in measurement_receiver {
+        deliver(measurements)?;
+    }
+    bail!("All sensor threads have exited")
+}
+
+fn build_outputs(config: &Config, output_directory: &Path) -> Result<Vec<OutputWorker>> {
+    let mut output_configs = config.outputs.clone();
+    if let Some(ref statsd_url) = config.statsd_url {
+        output_configs.push(legacy_statsd_output(statsd_url, config.statsd_port));
+    }
+    let context = OutputContext {
+        hostname: config.hostname.clone(),
+        output_directory: output_directory.to_path_buf(),
+        spool_directory: output_directory.join("spool"),
+    };
+    let mut outputs: Vec<OutputWorker> = Vec::new();
+    for output_config in &output_configs {
+        if !output_config.enabled {
+            info!("Output {} is disabled, skipping it", output_config.name());
+            continue;
+        }
+        if outputs.iter().any(|output| output.name() == output_config.name()) {
+            bail!("More than one output is named '{}', give each output of the same type a name", output_config.name());
+        }
+        let output = output_registry::build_output(output_config, &context)?;
+        info!("Sending to output {} of type {}", output_config.name(), output_config.output_type);
+        outputs.push(OutputWorker::spawn(output_config.name(), output, output_config.filter()));
+    }
+    if outputs.is_empty() {
+        bail!("No outputs are configured, add at least one to the outputs section");
+    }
+    Ok(outputs)
+}
 
-    let mut sensors: Vec<Box<Sensor>> = Vec::new();
-    for disk in config.disks {
-        sensors.push(Box::new(DiskSpaceSensor::new(OsString::from(disk))));
+// Without a sensors section the agent runs the sensors it always used to, one disk_space sensor
+// for each of the disks plus memory and CPU time with only the totals that were reported then
+fn sensor_configs(config: &Config) -> Result<Vec<SensorConfig>> {
+    if config.sensors.is_empty() {
+        let mut sensor_configs: Vec<SensorConfig> = config.disks
+            .iter()
+            .map(|disk| {
+                let mut options = Mapping::new();
+                options.insert(Value::from("path"), Value::from(disk.as_str()));
+                legacy_sensor("disk_space", Value::Mapping(options))
+            })
+            .collect();
+        let mut cpu_time_options = Mapping::new();
+        cpu_time_options.insert(Value::from("per_core"), Value::from(false));
+        cpu_time_options.insert(Value::from("per_mode"), Value::from(false));
+        sensor_configs.push(legacy_sensor("physical_memory", Value::Null));
+        sensor_configs.push(legacy_sensor("cpu_time", Value::Mapping(cpu_time_options)));
+        return Ok(sensor_configs);
     }
-    sensors.push(Box::new(PhysicalMemorySensor::new()));
-    sensors.push(Box::new(CpuTimeSensor::new()));
-    let num_sensors = sensors.len();
-    let sensor_pool = make_sensor_thread_pool(num_sensors as usize);
-    let mut last_update = SystemTime::now();
-    loop {
-        debug!("Running all sensors in parallel");
-        run_all_sensors_in_parallel(&sensor_pool, &mut sensors, &statsd_client);
-        sleep_until_target_time(last_update, update_interval);
-        last_update = SystemTime::now();
+    if !config.disks.is_empty() {
+        bail!("The disks setting can't be used along with sensors, list each disk as a disk_space sensor instead");
+    }
+    Ok(config.sensors.clone())
+}
+
+fn legacy_sensor(sensor_type: &str, options: Value) -> SensorConfig {
+    SensorConfig { sensor_type: sensor_type.to_string(), enabled: true, interval: None, options }
+}
+
+fn legacy_statsd_output(statsd_url: &str, statsd_port: u16) -> OutputConfig {
+    let mut options = Mapping::new();
+    options.insert(Value::from("host"), Value::from(statsd_url));
+    options.insert(Value::from("port"), Value::from(u64::from(statsd_port)));
+    OutputConfig {
+        output_type: "statsd".to_string(),
+        name: None,
+        enabled: true,
+        include: Vec::new(),
+        exclude: Vec::new(),
+        spool: None,
+        options: Value::Mapping(options),
+    }
+}
+
+// Tags that every measurement carries, unless the sensor has already set its own value
+fn create_default_tags(config: &Config) -> BTreeMap<String, String> {
+    let mut tags = config.tags.clone();
+    tags.insert(outputs::HOST_TAG.to_string(), config.hostname.clone());
+    if let Some(ref environment) = config.environment {
+        tags.insert(ENVIRONMENT_TAG.to_string(), environment.clone());
     }
+    tags
 }
 
 fn create_variable_bindings<'a>(
@@ -153,23 +344,48 @@ fn create_variable_bindings<'a>(
 fn substitute_variables(config: Config, bindings: &HashMap<&str, String>) -> Config {
     Config {
         hostname: substitute_bindings_in_string(&config.hostname, bindings),
-        statsd_url: substitute_bindings_in_string(&config.statsd_url, bindings),
+        environment: config.environment.map(|environment| substitute_bindings_in_string(&environment, bindings)),
+        tags: config.tags
+                    .into_iter()
+                    .map(|(key, value)| (key, substitute_bindings_in_string(&value, bindings)))
+                    .collect(),
+        statsd_url: config.statsd_url.map(|statsd_url| substitute_bindings_in_string(&statsd_url, bindings)),
         disks: config.disks
                      .iter()
-                     .map(|disk| substitute_bindings_in_string(&disk, bindings))
+                     .map(|disk| substitute_bindings_in_string(disk, bindings))
                      .collect(),
+        outputs: config.outputs
+                       .into_iter()
+                       .map(|output| OutputConfig {
+                           options: substitute_bindings_in_value(output.options, bindings),
+                           .. output
+                       })
+                       .collect(),
+        sensors: config.sensors
+                       .into_iter()
+                       .map(|sensor| SensorConfig {
+                           options: substitute_bindings_in_value(sensor.options, bindings),
+                           .. sensor
+                       })
+                       .collect(),
         .. config
     }
 }
 
 #[test]
-fn substitute_variables_substitutes_hostname_and_url() {
+fn substitute_variables_substitutes_hostname_url_tags_and_options() {
     let start_config = Config {
         hostname: "${variable-one}".to_string(),
-        statsd_url: "other ${variable}".to_string(),
+        environment: Some("${variable}".to_string()),
+        tags: vec![("role".to_string(), "${variable}-server".to_string())].into_iter().collect(),
+        statsd_url: Some("other ${variable}".to_string()),
         update_interval: Duration::from_millis(0),
+        sensor_timeout: Duration::from_millis(0),
+        overrun_policy: OverrunPolicy::Skip,
         statsd_port: 1234,
-        disks: vec!["disk-one".to_string()]
+        disks: vec!["${variable}/disk".to_string()],
+        outputs: vec![serde_yaml::from_str("type: influxdb\noptions:\n  url: http://${variable}:8086/write").unwrap()],
+        sensors: vec![serde_yaml::from_str("type: disk_space\noptions:\n  path: ${variable}/disk").unwrap()]
     };
 
     let mut bindings = HashMap::new();
@@ -178,10 +394,16 @@ fn substitute_variables_substitutes_hostname_and_url() {
 
     let expected_config = Config {
         hostname: "hostname".to_string(),
-        statsd_url: "other thing".to_string(),
+        environment: Some("thing".to_string()),
+        tags: vec![("role".to_string(), "thing-server".to_string())].into_iter().collect(),
+        statsd_url: Some("other thing".to_string()),
         update_interval: Duration::from_millis(0),
+        sensor_timeout: Duration::from_millis(0),
+        overrun_policy: OverrunPolicy::Skip,
         statsd_port: 1234,
-        disks: vec!["disk-one".to_string()]
+        disks: vec!["thing/disk".to_string()],
+        outputs: vec![serde_yaml::from_str("type: influxdb\noptions:\n  url: http://thing:8086/write").unwrap()],
+        sensors: vec![serde_yaml::from_str("type: disk_space\noptions:\n  path: thing/disk").unwrap()]
     };
 
     assert_eq!(
@@ -190,6 +412,30 @@ fn substitute_variables_substitutes_hostname_and_url() {
     );
 }
 
+#[test]
+fn sensor_configs_runs_the_old_sensors_for_a_config_without_sensors() {
+    // The configuration.yml that was installed before sensors and outputs could be configured
+    let config: Config = serde_yaml::from_str("---
+hostname: ${hostname}
+update_interval: 60 seconds
+statsd_url: stats.home
+statsd_port: 8125
+disks:
+  - ${output_directory}").unwrap();
+    let mut bindings = HashMap::new();
+    bindings.insert(OUTPUT_DIR_VARIABLE, "/var/log/lines".to_string());
+    let config = substitute_variables(config, &bindings);
+    assert_eq!(config.statsd_url, Some("stats.home".to_string()));
+    assert_eq!(sensor_configs(&config).unwrap(), vec![
+        serde_yaml::from_str::<SensorConfig>("type: disk_space\noptions:\n  path: /var/log/lines").unwrap(),
+        serde_yaml::from_str("type: physical_memory").unwrap(),
+        serde_yaml::from_str("type: cpu_time\noptions:\n  per_core: false\n  per_mode: false").unwrap(),
+    ]);
+    for sensor_config in sensor_configs(&config).unwrap() {
+        assert!(registry::build_sensor(&sensor_config).is_ok());
+    }
+}
+
 fn substitute_bindings_in_string(template: &str, bindings: &HashMap<&str, String>) -> String {
     let mut substituted_string = String::new();
     let mut last_match_end_index = 0;
@@ -237,50 +483,36 @@ fn substitute_bindings_in_string_test() {
     );
 }
 
-fn make_statsd_client(host: &str, port: u16, metrics_prefix: &str) -> StatsdClient {
-    let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
-    socket.set_nonblocking(true).unwrap();
-    let host = (host, port);
-    // Use an unbuffered UdpMetricsSink because we only occasionally emit metrics
-    let udp_sink = UdpMetricSink::from(&host, socket).unwrap();
-    let queuing_sink = QueuingMetricSink::from(udp_sink);
-    StatsdClient::from_sink(metrics_prefix, queuing_sink)
-}
-
-fn make_sensor_thread_pool(num_sensors: usize) -> ThreadPool {
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(num_sensors as usize)
-        .thread_name(|thread_index| "sensor-pool-thread-".to_string() + &thread_index.to_string())
-        .build()
-        .unwrap()
-}
-
-fn run_all_sensors_in_parallel<T>(
-    sensor_pool: &ThreadPool,
-    sensors: &mut Vec<Box<T>>,
-    statsd_client: &StatsdClient,
-) where
-    T: Sensor + ?Sized,
-{
-    sensor_pool.scope(|scope| {
-        for sensor in sensors {
-            scope.spawn(move |_| sensor.sense(statsd_client));
-        }
-    });
-}
-
-fn sleep_until_target_time(last_wakeup: SystemTime, target_interval: Duration) {
-    let time_until_next_wakeup =
-        target_interval - SystemTime::now().duration_since(last_wakeup).unwrap();
-    debug!(
-        "Time until next wakeup: {:.3}s",
-        duration_in_seconds(&time_until_next_wakeup)
-    );
-    if time_until_next_wakeup > Duration::from_millis(0) {
-        thread::sleep(time_until_next_wakeup);
+// Only strings can contain variables, but they can be nested anywhere in a sensor's options
+fn substitute_bindings_in_value(value: Value, bindings: &HashMap<&str, String>) -> Value {
+    match value {
+        Value::String(string) => Value::String(substitute_bindings_in_string(&string, bindings)),
+        Value::Sequence(sequence) => Value::Sequence(
+            sequence.into_iter().map(|item| substitute_bindings_in_value(item, bindings)).collect()
+        ),
+        Value::Mapping(mapping) => Value::Mapping(
+            mapping.into_iter().map(|(key, item)| (key, substitute_bindings_in_value(item, bindings))).collect()
+        ),
+        other => other,
     }
 }
 
-fn duration_in_seconds(duration: &Duration) -> f64 {
-    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1_000_000_000 as f64
+// Each sensor runs on its own schedule, so a sensor with a long interval or a slow run doesn't
+// hold up the others
+fn spawn_sensor_loop(
+    mut runner: SensorRunner,
+    mut schedule: Schedule,
+    timeout: Duration,
+    measurement_sender: Sender<Vec<Measurement>>,
+) {
+    thread::Builder::new()
+        .name("schedule-".to_string() + runner.name())
+        .spawn(move || loop {
+            let measurements = runner.run(timeout);
+            if !measurements.is_empty() && measurement_sender.send(measurements).is_err() {
+                break;
+            }
+            schedule.wait();
+        })
+        .unwrap();
 }
@@ -0,0 +1,73 @@
+/// Picks names out by glob patterns, where `*` matches any run of characters and `?` matches any
+/// single character. A name is kept if it matches an include pattern, or there are none, and
+/// doesn't match any exclude pattern.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GlobFilter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl GlobFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> GlobFilter {
+        GlobFilter { include: include.to_vec(), exclude: exclude.to_vec() }
+    }
+
+    /// Whether the filter keeps everything, so callers can skip checking each name
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        let included = self.include.is_empty() || self.include.iter().any(|pattern| glob_matches(pattern, name));
+        included && !self.exclude.iter().any(|pattern| glob_matches(pattern, name))
+    }
+}
+
+pub fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut pattern_index, mut text_index) = (0, 0);
+    // Where to pick up again if what follows the last `*` stops matching: the pattern just after
+    // the star and the text position the star should now stretch to
+    let mut backtrack: Option<(usize, usize)> = None;
+    while text_index < text.len() {
+        match pattern.get(pattern_index) {
+            Some(&'*') => {
+                backtrack = Some((pattern_index + 1, text_index));
+                pattern_index += 1;
+            },
+            Some(&c) if c == '?' || c == text[text_index] => {
+                pattern_index += 1;
+                text_index += 1;
+            },
+            _ => match backtrack {
+                Some((star_pattern_index, star_text_index)) => {
+                    pattern_index = star_pattern_index;
+                    text_index = star_text_index + 1;
+                    backtrack = Some((star_pattern_index, star_text_index + 1));
+                },
+                None => return false,
+            },
+        }
+    }
+    pattern[pattern_index..].iter().all(|&c| c == '*')
+}
+
+#[test]
+fn glob_matches_stars_and_question_marks() {
+    assert!(glob_matches("drive.*", "drive.free_bytes"));
+    assert!(glob_matches("*.free_*", "drive.free_bytes"));
+    assert!(glob_matches("eth?", "eth0"));
+    assert!(glob_matches("*", ""));
+    assert!(!glob_matches("eth?", "eth10"));
+    assert!(!glob_matches("drive.*", "cpu_time.busy_time"));
+}
+
+#[test]
+fn matches_applies_excludes_after_includes() {
+    let filter = GlobFilter::new(&["drive.*".to_string(), "cpu_time.*".to_string()], &["*.total_bytes".to_string()]);
+    assert!(filter.matches("drive.free_bytes"));
+    assert!(!filter.matches("drive.total_bytes"));
+    assert!(!filter.matches("physical_memory.free_bytes"));
+    assert!(GlobFilter::default().matches("anything"));
+}
@@ -0,0 +1,153 @@
+use self_metrics;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+static FIRST_RETRY_DELAY: Duration = Duration::from_millis(500);
+static MAX_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// The parts of an `http://` URL needed to make a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Url {
+    pub host: String,
+    pub port: u16,
+    // Including the query string, if there is one
+    pub path: String,
+}
+
+/// Parses a plain `http://host[:port][/path][?query]` URL. There's no TLS support, so HTTPS
+/// endpoints need a local proxy in front of them.
+pub fn parse_url(url: &str) -> Result<Url> {
+    let invalid = |reason: &str| Error::new(ErrorKind::InvalidInput, format!("Invalid URL '{}': {}", url, reason));
+    let rest = match url.strip_prefix("http://") {
+        Some(rest) => rest,
+        None => return Err(invalid("only http:// URLs are supported")),
+    };
+    let (authority, path) = match rest.find(&['/', '?'][..]) {
+        Some(index) if rest[index..].starts_with('?') => (&rest[..index], "/".to_string() + &rest[index..]),
+        Some(index) => (&rest[..index], rest[index..].to_string()),
+        None => (rest, "/".to_string()),
+    };
+    let (host, port) = match authority.rfind(':') {
+        Some(index) if !authority.ends_with(']') => {
+            let port = authority[index + 1..].parse().map_err(|_| invalid("the port is not a number"))?;
+            (&authority[..index], port)
+        },
+        _ => (authority, 80),
+    };
+    if host.is_empty() {
+        return Err(invalid("there is no host"));
+    }
+    Ok(Url { host: host.to_string(), port, path })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub body: String,
+}
+
+impl Response {
+    pub fn is_success(&self) -> bool {
+        self.status >= 200 && self.status < 300
+    }
+}
+
+/// Sends a POST request on a new connection and waits for the whole response
+pub fn post(url: &Url, content_type: &str, headers: &[(&str, &str)], body: &[u8], timeout: Duration) -> Result<Response> {
+    let mut stream = connect(url, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let mut request = format!("POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+                              url.path, url.host, url.port, content_type, body.len());
+    for &(name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    parse_response(&response)
+}
+
+/// Posts the body, retrying with exponential backoff while the request fails in a way that might
+/// not happen again. Server errors and throttling are worth retrying, the server rejecting what
+/// was sent isn't. Each retry is counted in the named self-metric.
+pub fn post_with_retry(
+    url: &Url,
+    content_type: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+    timeout: Duration,
+    max_attempts: u32,
+    retries_metric: &str,
+) -> Result<()> {
+    let mut delay = FIRST_RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        let error = match post(url, content_type, headers, body, timeout) {
+            Ok(ref response) if response.is_success() => return Ok(()),
+            Ok(response) => {
+                let error = Error::other(format!("HTTP {}: {}", response.status, response.body.trim()));
+                if response.status < 500 && response.status != 429 {
+                    return Err(error);
+                }
+                error
+            },
+            Err(e) => e,
+        };
+        if attempt >= max_attempts {
+            return Err(error);
+        }
+        debug!("Error posting to {}:{}{}, retrying in {:?}: {}", url.host, url.port, url.path, delay, error);
+        self_metrics::count(retries_metric, 1);
+        thread::sleep(delay);
+        delay = ::std::cmp::min(delay * 2, MAX_RETRY_DELAY);
+        attempt += 1;
+    }
+}
+
+fn connect(url: &Url, timeout: Duration) -> Result<TcpStream> {
+    let mut last_error = Error::new(ErrorKind::NotFound, format!("No addresses found for {}", url.host));
+    for address in (url.host.as_str(), url.port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+fn parse_response(response: &[u8]) -> Result<Response> {
+    let response = String::from_utf8_lossy(response);
+    let status = response
+        .lines()
+        .next()
+        .and_then(|status_line| status_line.split_whitespace().nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Malformed HTTP response"))?;
+    let body = match response.find("\r\n\r\n") {
+        Some(index) => response[index + 4..].to_string(),
+        None => String::new(),
+    };
+    Ok(Response { status, body })
+}
+
+#[test]
+fn parse_url_splits_host_port_and_path() {
+    assert_eq!(parse_url("http://influx.home:8086/api/v2/write?org=home&bucket=lines").unwrap(),
+               Url { host: "influx.home".to_string(), port: 8086, path: "/api/v2/write?org=home&bucket=lines".to_string() });
+    assert_eq!(parse_url("http://localhost").unwrap(),
+               Url { host: "localhost".to_string(), port: 80, path: "/".to_string() });
+    assert!(parse_url("https://localhost/write").is_err());
+}
+
+#[test]
+fn parse_response_reads_status_and_body() {
+    let response = parse_response(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 9\r\n\r\nbad field").unwrap();
+    assert_eq!(response, Response { status: 400, body: "bad field".to_string() });
+    assert!(!response.is_success());
+}
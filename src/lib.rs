@@ -1,35 +1,43 @@
 #[macro_use] extern crate log;
 #[macro_use] extern crate lazy_static;
+#[macro_use] extern crate serde_derive;
 
 extern crate cadence;
+extern crate serde;
 
 pub mod sensors;
+pub mod measurement;
+pub mod outputs;
+pub mod self_metrics;
+pub mod log_limiter;
+pub mod runner;
+pub mod schedule;
+pub mod http;
+pub mod filter;
+
+pub use measurement::{Measurement, MetricKind, Unit};
 
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use cadence::prelude::*;
-use cadence::StatsdClient;
+use std::io::Result;
 use std::f64;
 
 static START_TIME: SystemTime = UNIX_EPOCH;
 static PERIOD: Duration = Duration::from_secs(30 * 60);
 
 pub trait Sensor: Send {
-    fn sense(&mut self, stats_pipeline: &StatsdClient);
+    fn sense(&mut self) -> Result<Vec<Measurement>>;
 }
 
 pub struct DummySensor(pub String, pub i64);
 
 impl Sensor for DummySensor {
-    fn sense(&mut self, statsd_client: &StatsdClient) {
+    fn sense(&mut self) -> Result<Vec<Measurement>> {
         let now = SystemTime::now();
         let place_in_interval = (now.duration_since(START_TIME).unwrap().as_secs() % PERIOD.as_secs()) as f64 / PERIOD.as_secs() as f64;
         let sin_parameter = place_in_interval * f64::consts::PI * 2.0;
         let metric_name = "test.".to_string() + &self.0;
         let curr_value = self.1 + (self.1 as f64 * f64::sin(sin_parameter)).round() as i64;
         info!("Sense called for Sensor {}, emitting value {}", self.0, curr_value);
-        if let Err(e) = statsd_client.count(&metric_name, curr_value) {
-            error!("Encountered and ignoring error sending stats for metric name {} and value {}: {:?}",
-                   &metric_name, curr_value, e);
-        }
+        Ok(vec![Measurement::new(&metric_name, curr_value as f64, MetricKind::Gauge, Unit::None)])
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,43 @@
+use std::time::{Duration, Instant};
+
+/// Logs an error at most once per interval, so that something failing on every tick doesn't
+/// flood the log. Errors that arrive in between are counted and mentioned in the next message.
+pub struct LogLimiter {
+    interval: Duration,
+    last_logged: Option<Instant>,
+    suppressed: u64,
+}
+
+impl LogLimiter {
+    pub fn new(interval: Duration) -> LogLimiter {
+        LogLimiter { interval, last_logged: None, suppressed: 0 }
+    }
+
+    pub fn error(&mut self, message: &str) {
+        let now = Instant::now();
+        let due = match self.last_logged {
+            Some(last_logged) => now.duration_since(last_logged) >= self.interval,
+            None => true,
+        };
+        if !due {
+            self.suppressed += 1;
+            return;
+        }
+        if self.suppressed > 0 {
+            error!("{} ({} similar errors suppressed)", message, self.suppressed);
+        } else {
+            error!("{}", message);
+        }
+        self.last_logged = Some(now);
+        self.suppressed = 0;
+    }
+}
+
+#[test]
+fn error_suppresses_messages_within_interval() {
+    let mut limiter = LogLimiter::new(Duration::from_secs(60));
+    limiter.error("first");
+    limiter.error("second");
+    limiter.error("third");
+    assert_eq!(limiter.suppressed, 2);
+}
@@ -0,0 +1,90 @@
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+/// How a value should be aggregated by whatever receives it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MetricKind {
+    /// An absolute level, like free bytes, where only the latest value matters
+    Gauge,
+    /// A delta since the last report, which aggregators sum over their flush interval
+    Counter,
+    /// A duration in milliseconds, which aggregators summarize into percentiles
+    Timer,
+    /// An arbitrary sampled value, which aggregators summarize into percentiles
+    Histogram,
+    /// An occurrence of a value, which aggregators count the distinct values of
+    Set,
+    /// A sampled value whose percentiles are computed globally across hosts rather than per host
+    Distribution,
+}
+
+/// What a value is measured in, so outputs don't need to guess from the metric name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Unit {
+    Bytes,
+    Percent,
+    Milliseconds,
+    None,
+}
+
+/// A single value observed by a sensor, independent of where it ends up being sent. Tags say
+/// which thing the value is about (a mount point, a CPU core) rather than baking it into the name
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Measurement {
+    pub name: String,
+    pub value: f64,
+    pub kind: MetricKind,
+    pub unit: Unit,
+    pub tags: BTreeMap<String, String>,
+    // The tags that tell apart series of the same name, in the order outputs without tags fold
+    // their values into the name. Other tags, like the host or environment, are left out of it.
+    #[serde(default)]
+    pub name_tags: Vec<String>,
+    pub timestamp: SystemTime,
+    // The fraction of occurrences this value stands for, so aggregators can scale counts back up
+    pub sample_rate: f64,
+}
+
+impl Measurement {
+    pub fn new(name: &str, value: f64, kind: MetricKind, unit: Unit) -> Measurement {
+        Measurement {
+            name: name.to_string(),
+            value,
+            kind,
+            unit,
+            tags: BTreeMap::new(),
+            name_tags: Vec::new(),
+            timestamp: SystemTime::now(),
+            sample_rate: 1.0,
+        }
+    }
+
+    pub fn with_tag(mut self, key: &str, value: &str) -> Measurement {
+        self.tags.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Adds a tag that is also part of the name where tags can't be sent, like the path of a
+    /// drive or the number of a CPU core
+    pub fn with_name_tag(mut self, key: &str, value: &str) -> Measurement {
+        if !self.name_tags.iter().any(|name_tag| name_tag == key) {
+            self.name_tags.push(key.to_string());
+        }
+        self.with_tag(key, value)
+    }
+
+    pub fn with_sample_rate(mut self, sample_rate: f64) -> Measurement {
+        self.sample_rate = sample_rate;
+        self
+    }
+
+    /// Adds tags that apply to everything, like the host, without replacing any tag of the same
+    /// name that the sensor chose itself
+    pub fn add_default_tags(&mut self, tags: &BTreeMap<String, String>) {
+        for (key, value) in tags {
+            if !self.tags.contains_key(key) {
+                self.tags.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
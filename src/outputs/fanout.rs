@@ -0,0 +1,165 @@
+use super::Output;
+use filter::GlobFilter;
+use log_limiter::LogLimiter;
+use measurement::Measurement;
+#[cfg(test)]
+use measurement::{MetricKind, Unit};
+use self_metrics;
+use std::io::Result;
+use std::sync::Arc;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// How many batches can wait for an output before new ones are dropped
+const QUEUE_CAPACITY: usize = 32;
+static ERROR_LOG_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Sends to one output on its own thread, so that an output that is slow or failing only loses
+/// its own measurements rather than holding up every other output
+pub struct OutputWorker {
+    name: String,
+    sender: SyncSender<Arc<Vec<Measurement>>>,
+    thread: JoinHandle<()>,
+    drop_log: LogLimiter,
+}
+
+impl OutputWorker {
+    pub fn spawn(name: &str, mut output: Box<dyn Output>, filter: GlobFilter) -> OutputWorker {
+        let (sender, receiver) = mpsc::sync_channel::<Arc<Vec<Measurement>>>(QUEUE_CAPACITY);
+        let thread_name = name.to_string();
+        let thread = thread::Builder::new()
+            .name("output-".to_string() + name)
+            .spawn(move || {
+                let mut error_log = LogLimiter::new(ERROR_LOG_INTERVAL);
+                let tags = [("output", thread_name.as_str())];
+                for measurements in receiver {
+                    let started = Instant::now();
+                    let result = if filter.is_empty() {
+                        output.send(&measurements)
+                    } else {
+                        send_filtered(&mut *output, &filter, &measurements)
+                    };
+                    self_metrics::time_with_tags("output.send_time", &tags, started.elapsed());
+                    if let Err(e) = result {
+                        error_log.error(&format!("Error sending metrics to output {}, continuing: {}", thread_name, e));
+                    }
+                }
+            })
+            .unwrap();
+        OutputWorker { name: name.to_string(), sender, thread, drop_log: LogLimiter::new(ERROR_LOG_INTERVAL) }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Queues measurements for the output without waiting, dropping them if the output has
+    /// fallen too far behind
+    pub fn send(&mut self, measurements: &Arc<Vec<Measurement>>) {
+        match self.sender.try_send(measurements.clone()) {
+            Ok(()) => {},
+            Err(TrySendError::Full(_)) => {
+                self.drop_log.error(&format!("Output {} is falling behind, dropping {} measurements",
+                                             self.name, measurements.len()));
+                self_metrics::count_with_tags("output.dropped_batches", &[("output", &self.name)], 1);
+            },
+            Err(TrySendError::Disconnected(_)) => {
+                self.drop_log.error(&format!("Thread for output {} has exited, dropping {} measurements",
+                                             self.name, measurements.len()));
+                self_metrics::count_with_tags("output.dropped_batches", &[("output", &self.name)], 1);
+            }
+        }
+    }
+
+    /// Waits for the output to send everything already queued for it
+    pub fn finish(self) {
+        let OutputWorker { name, sender, thread, .. } = self;
+        drop(sender);
+        if thread.join().is_err() {
+            error!("Thread for output {} panicked", name);
+        }
+    }
+}
+
+fn send_filtered(output: &mut dyn Output, filter: &GlobFilter, measurements: &[Measurement]) -> Result<()> {
+    let filtered: Vec<Measurement> = measurements
+        .iter()
+        .filter(|measurement| filter.matches(&measurement.name))
+        .cloned()
+        .collect();
+    if filtered.is_empty() {
+        return Ok(());
+    }
+    output.send(&filtered)
+}
+
+#[cfg(test)]
+struct SlowOutput(mpsc::Sender<usize>);
+
+#[cfg(test)]
+impl Output for SlowOutput {
+    fn send(&mut self, measurements: &[Measurement]) -> Result<()> {
+        thread::sleep(Duration::from_millis(200));
+        self.0.send(measurements.len()).unwrap();
+        Ok(())
+    }
+}
+
+// Reports each batch as it starts sending it, then holds on to it until released
+#[cfg(test)]
+struct BlockedOutput {
+    started: mpsc::Sender<usize>,
+    release: mpsc::Receiver<()>,
+}
+
+#[cfg(test)]
+impl Output for BlockedOutput {
+    fn send(&mut self, measurements: &[Measurement]) -> Result<()> {
+        self.started.send(measurements.len()).unwrap();
+        let _ = self.release.recv();
+        Ok(())
+    }
+}
+
+#[test]
+fn send_drops_batches_instead_of_waiting_for_slow_output() {
+    let (started_sender, started_receiver) = mpsc::channel();
+    let (release_sender, release_receiver) = mpsc::channel();
+    let output = BlockedOutput { started: started_sender, release: release_receiver };
+    let mut worker = OutputWorker::spawn("blocked", Box::new(output), GlobFilter::default());
+    let measurements = Arc::new(vec![Measurement::new("cpu_time.busy_time", 1.0, MetricKind::Gauge, Unit::Percent)]);
+    worker.send(&measurements);
+    assert_eq!(started_receiver.recv().unwrap(), 1);
+    // The output is stuck on the first batch, so only a full queue's worth more can wait for it
+    for _ in 0..QUEUE_CAPACITY * 2 {
+        worker.send(&measurements);
+    }
+    drop(release_sender);
+    worker.finish();
+    assert_eq!(started_receiver.try_iter().count(), QUEUE_CAPACITY);
+}
+
+#[test]
+fn finish_waits_for_queued_batches() {
+    let (sent_sender, sent_receiver) = mpsc::channel();
+    let mut worker = OutputWorker::spawn("slow", Box::new(SlowOutput(sent_sender)), GlobFilter::default());
+    let measurements = Arc::new(vec![Measurement::new("cpu_time.busy_time", 1.0, MetricKind::Gauge, Unit::Percent)]);
+    worker.send(&measurements);
+    worker.send(&measurements);
+    worker.finish();
+    assert_eq!(sent_receiver.try_iter().count(), 2);
+}
+
+#[test]
+fn send_filtered_only_passes_matching_measurements() {
+    let (sent_sender, sent_receiver) = mpsc::channel();
+    let mut output = SlowOutput(sent_sender);
+    let filter = GlobFilter::new(&["drive.*".to_string()], &[]);
+    let measurements = vec![
+        Measurement::new("drive.free_bytes", 1.0, MetricKind::Gauge, Unit::Bytes),
+        Measurement::new("cpu_time.busy_time", 1.0, MetricKind::Gauge, Unit::Percent),
+    ];
+    send_filtered(&mut output, &filter, &measurements).unwrap();
+    assert_eq!(sent_receiver.recv().unwrap(), 1);
+}
@@ -0,0 +1,252 @@
+extern crate log4rs;
+extern crate serde_json;
+
+use super::{Output, HOST_TAG};
+#[cfg(test)]
+use super::test_measurement;
+use measurement::{Measurement, MetricKind, Unit};
+use self::log4rs::append::rolling_file::policy::compound::roll::Roll;
+use self::log4rs::append::rolling_file::policy::compound::roll::fixed_window::FixedWindowRoller;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+#[cfg(test)]
+use std::io::Read;
+use std::io::{BufWriter, Error, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+/// How measurements are written out, one per line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileFormat {
+    /// A JSON object per line with the timestamp, host, name, value, kind, unit and other tags
+    #[default]
+    JsonLines,
+    /// The same fields as comma separated values under a header, with tags as `key=value;...`
+    Csv,
+}
+
+static CSV_HEADER: &str = "timestamp,host,name,value,kind,unit,tags\n";
+
+/// When to start a new file and what to do with the old ones. Old files are renamed following a
+/// pattern like `measurements.{}.jsonl.gz` by the same fixed window roller that log4rs.yml uses,
+/// which also gzips them when the pattern ends in `.gz`.
+pub struct Rotation {
+    max_bytes: Option<u64>,
+    max_age: Option<Duration>,
+    roller: FixedWindowRoller,
+}
+
+impl Rotation {
+    pub fn new(max_bytes: Option<u64>, max_age: Option<Duration>, pattern: &str, count: u32) -> Result<Rotation> {
+        let roller = FixedWindowRoller::builder().base(1).build(pattern, count).map_err(|e| {
+            Error::new(ErrorKind::InvalidInput, format!("Invalid rotation pattern '{}': {}", pattern, e))
+        })?;
+        Ok(Rotation { max_bytes, max_age, roller })
+    }
+}
+
+/// Appends every measurement to a local file, for debugging and for capacity studies that want
+/// the raw values rather than whatever an aggregator kept of them
+pub struct FileOutput {
+    path: PathBuf,
+    format: FileFormat,
+    rotation: Option<Rotation>,
+    file: Option<BufWriter<File>>,
+    bytes_written: u64,
+    opened_at: Instant,
+}
+
+impl FileOutput {
+    pub fn new(path: &Path, format: FileFormat, rotation: Option<Rotation>) -> Result<FileOutput> {
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory).map_err(|e| {
+                Error::new(e.kind(), format!("Error creating directory {}: {}", directory.display(), e))
+            })?;
+        }
+        Ok(FileOutput { path: path.to_path_buf(), format, rotation, file: None, bytes_written: 0, opened_at: Instant::now() })
+    }
+
+    fn open(&mut self) -> Result<()> {
+        let mut file = BufWriter::new(OpenOptions::new().create(true).append(true).open(&self.path).map_err(|e| {
+            Error::new(e.kind(), format!("Error opening {}: {}", self.path.display(), e))
+        })?);
+        // Carrying on with a file from before a restart counts what is already in it
+        self.bytes_written = fs::metadata(&self.path)?.len();
+        self.opened_at = Instant::now();
+        if self.bytes_written == 0 && self.format == FileFormat::Csv {
+            file.write_all(CSV_HEADER.as_bytes())?;
+            self.bytes_written += CSV_HEADER.len() as u64;
+        }
+        self.file = Some(file);
+        Ok(())
+    }
+
+    fn rotation_due(&self) -> bool {
+        match self.rotation {
+            Some(ref rotation) => {
+                rotation.max_bytes.is_some_and(|max_bytes| self.bytes_written >= max_bytes) ||
+                    rotation.max_age.is_some_and(|max_age| self.opened_at.elapsed() >= max_age)
+            },
+            None => false,
+        }
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        if let Some(mut file) = self.file.take() {
+            file.flush()?;
+        }
+        if let Some(ref rotation) = self.rotation {
+            rotation.roller.roll(&self.path).map_err(|e| {
+                Error::other(format!("Error rotating {}: {}", self.path.display(), e))
+            })?;
+        }
+        Ok(())
+    }
+}
+
+impl Output for FileOutput {
+    fn send(&mut self, measurements: &[Measurement]) -> Result<()> {
+        if self.file.is_some() && self.rotation_due() {
+            self.rotate()?;
+        }
+        if self.file.is_none() {
+            self.open()?;
+        }
+        let mut written = 0;
+        {
+            let file = self.file.as_mut().unwrap();
+            for measurement in measurements {
+                let line = match self.format {
+                    FileFormat::JsonLines => format_json_line(measurement)?,
+                    FileFormat::Csv => format_csv_line(measurement),
+                };
+                file.write_all(line.as_bytes())?;
+                written += line.len() as u64;
+            }
+            // Each batch goes to disk as it arrives, so a crash only loses the batch in progress
+            file.flush()?;
+        }
+        self.bytes_written += written;
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    timestamp: u64,
+    host: Option<&'a str>,
+    name: &'a str,
+    value: f64,
+    kind: &'static str,
+    unit: &'static str,
+    tags: BTreeMap<&'a str, &'a str>,
+}
+
+// Milliseconds since the Unix epoch, which every analysis tool can read
+fn timestamp_millis(measurement: &Measurement) -> u64 {
+    let since_epoch = measurement.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+    since_epoch.as_secs() * 1000 + u64::from(since_epoch.subsec_millis())
+}
+
+pub fn kind_name(kind: MetricKind) -> &'static str {
+    match kind {
+        MetricKind::Gauge => "gauge",
+        MetricKind::Counter => "counter",
+        MetricKind::Timer => "timer",
+        MetricKind::Histogram => "histogram",
+        MetricKind::Set => "set",
+        MetricKind::Distribution => "distribution",
+    }
+}
+
+pub fn unit_name(unit: Unit) -> &'static str {
+    match unit {
+        Unit::Bytes => "bytes",
+        Unit::Percent => "percent",
+        Unit::Milliseconds => "milliseconds",
+        Unit::None => "",
+    }
+}
+
+fn other_tags(measurement: &Measurement) -> BTreeMap<&str, &str> {
+    measurement.tags
+        .iter()
+        .filter(|&(key, _)| key != HOST_TAG)
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+        .collect()
+}
+
+/// A measurement as a line of JSON, which is also what `--dry-run` prints
+pub fn format_json_line(measurement: &Measurement) -> Result<String> {
+    let record = Record {
+        timestamp: timestamp_millis(measurement),
+        host: measurement.tags.get(HOST_TAG).map(|host| host.as_str()),
+        name: &measurement.name,
+        value: measurement.value,
+        kind: kind_name(measurement.kind),
+        unit: unit_name(measurement.unit),
+        tags: other_tags(measurement),
+    };
+    let json = serde_json::to_string(&record).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    Ok(json + "\n")
+}
+
+fn format_csv_line(measurement: &Measurement) -> String {
+    let tags: Vec<String> = other_tags(measurement)
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect();
+    let fields = [
+        timestamp_millis(measurement).to_string(),
+        csv_field(measurement.tags.get(HOST_TAG).map_or("", |host| host.as_str())),
+        csv_field(&measurement.name),
+        measurement.value.to_string(),
+        kind_name(measurement.kind).to_string(),
+        unit_name(measurement.unit).to_string(),
+        csv_field(&tags.join(";")),
+    ];
+    fields.join(",") + "\n"
+}
+
+// Quotes a field if it has anything in it that would otherwise split it, doubling any quotes
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+#[cfg(test)]
+fn tagged_measurement() -> Measurement {
+    test_measurement("drive.free_bytes", 1024.0, MetricKind::Gauge, Unit::Bytes)
+        .with_tag("host", "web-1")
+        .with_tag("mount", "/mnt/a,b")
+}
+
+#[test]
+fn format_lines_carry_host_tags_and_timestamp() {
+    assert_eq!(format_json_line(&tagged_measurement()).unwrap(),
+               "{\"timestamp\":1500000000250,\"host\":\"web-1\",\"name\":\"drive.free_bytes\",\"value\":1024.0,\
+                \"kind\":\"gauge\",\"unit\":\"bytes\",\"tags\":{\"mount\":\"/mnt/a,b\"}}\n");
+    assert_eq!(format_csv_line(&tagged_measurement()),
+               "1500000000250,web-1,drive.free_bytes,1024,gauge,bytes,\"mount=/mnt/a,b\"\n");
+}
+
+#[test]
+fn send_rotates_to_gzipped_files_past_size_limit() {
+    let directory = ::std::env::temp_dir().join(format!("lines-file-output-{}", ::std::process::id()));
+    let _ = fs::remove_dir_all(&directory);
+    let path = directory.join("measurements.csv");
+    let pattern = directory.join("measurements.{}.csv.gz");
+    let rotation = Rotation::new(Some(100), None, &pattern.to_string_lossy(), 3).unwrap();
+    let mut output = FileOutput::new(&path, FileFormat::Csv, Some(rotation)).unwrap();
+    output.send(&[tagged_measurement(), tagged_measurement()]).unwrap();
+    output.send(&[tagged_measurement()]).unwrap();
+    let mut current = String::new();
+    File::open(&path).unwrap().read_to_string(&mut current).unwrap();
+    assert_eq!(current, CSV_HEADER.to_string() + &format_csv_line(&tagged_measurement()));
+    assert!(directory.join("measurements.1.csv.gz").exists());
+    fs::remove_dir_all(&directory).unwrap();
+}
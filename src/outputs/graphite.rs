@@ -0,0 +1,196 @@
+use super::{flatten_name, write_reconnecting, Output};
+#[cfg(test)]
+use super::HOST_TAG;
+use measurement::Measurement;
+#[cfg(test)]
+use measurement::{MetricKind, Unit};
+use self_metrics;
+use std::io::{Error, ErrorKind, Result};
+#[cfg(test)]
+use std::io::Read;
+use std::net::{TcpStream, ToSocketAddrs};
+#[cfg(test)]
+use std::net::TcpListener;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+static CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+static WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+// Carbon caps how much it reads from a pickle in one go, so big sends are split up
+const MAX_BATCH_SIZE: usize = 500;
+
+/// The default Graphite path, which keeps the hostname prefix that statsd names have
+pub static DEFAULT_PATH_TEMPLATE: &str = "{host}.{name}";
+
+/// How metrics are written to Carbon
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GraphiteProtocol {
+    /// One `path value timestamp` line per metric, usually on port 2003
+    #[default]
+    Plaintext,
+    /// Batches of metrics as a Python pickle, usually on port 2004
+    Pickle,
+}
+
+/// Writes metrics to Carbon over TCP, connecting again after the connection breaks. Large sends
+/// go out in several batches, and a failure part way through fails the whole send, so when it is
+/// spooled the batches that had already been written are sent again. Delivery is at least once,
+/// which Carbon copes with since a repeated point overwrites itself.
+pub struct GraphiteOutput {
+    address: String,
+    path_template: String,
+    protocol: GraphiteProtocol,
+    stream: Option<TcpStream>,
+}
+
+impl GraphiteOutput {
+    /// Creates an output that connects on first send. In the path template, `{name}` is the
+    /// metric name with its tag values folded in and any other `{tag}` is that tag's value.
+    pub fn new(address: &str, path_template: &str, protocol: GraphiteProtocol) -> GraphiteOutput {
+        GraphiteOutput {
+            address: address.to_string(),
+            path_template: path_template.to_string(),
+            protocol,
+            stream: None,
+        }
+    }
+
+    fn write_batch(&mut self, batch: &[u8]) -> Result<()> {
+        let address = &self.address;
+        write_reconnecting(&mut self.stream, || connect(address), batch,
+                           &format!("Graphite at {}", address), "graphite.reconnects")
+    }
+}
+
+fn connect(address: &str) -> Result<TcpStream> {
+    let mut last_error = Error::new(ErrorKind::NotFound, format!("No addresses found for {}", address));
+    for socket_address in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&socket_address, CONNECT_TIMEOUT) {
+            Ok(stream) => {
+                stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                return Ok(stream);
+            },
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+impl Output for GraphiteOutput {
+    fn send(&mut self, measurements: &[Measurement]) -> Result<()> {
+        let metrics: Vec<(String, f64, f64)> = measurements
+            .iter()
+            .filter(|measurement| measurement.value.is_finite())
+            .map(|measurement| {
+                (graphite_path(&self.path_template, measurement), unix_time(measurement.timestamp), measurement.value)
+            })
+            .collect();
+        for batch in metrics.chunks(MAX_BATCH_SIZE) {
+            let payload = match self.protocol {
+                GraphiteProtocol::Plaintext => plaintext_lines(batch),
+                GraphiteProtocol::Pickle => pickle_message(batch),
+            };
+            if let Err(e) = self.write_batch(&payload) {
+                self_metrics::count("graphite.send_errors", batch.len() as u64);
+                return Err(Error::new(e.kind(), format!("Error sending {} metrics to Graphite at {}: {}",
+                                                        batch.len(), self.address, e)));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn graphite_path(template: &str, measurement: &Measurement) -> String {
+    let mut path = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        path.push_str(&rest[..start]);
+        let placeholder = &rest[start + 1..end];
+        let value = match placeholder {
+            "name" => flatten_name(measurement),
+            tag => measurement.tags.get(tag).map(|value| path_part(value)).unwrap_or_else(|| "_".to_string()),
+        };
+        path.push_str(&value);
+        rest = &rest[end + 1..];
+    }
+    path.push_str(rest);
+    path
+}
+
+// Tag values go into the path as they are, apart from whitespace which would split the line
+fn path_part(value: &str) -> String {
+    value.chars().map(|c| if c.is_whitespace() { '_' } else { c }).collect()
+}
+
+fn unix_time(timestamp: SystemTime) -> f64 {
+    let since_epoch = timestamp.duration_since(UNIX_EPOCH).unwrap_or_else(|_| Duration::from_secs(0));
+    since_epoch.as_secs() as f64
+}
+
+fn plaintext_lines(metrics: &[(String, f64, f64)]) -> Vec<u8> {
+    let mut lines = String::new();
+    for &(ref path, timestamp, value) in metrics {
+        lines.push_str(&format!("{} {} {}\n", path, value, timestamp));
+    }
+    lines.into_bytes()
+}
+
+// A list of `(path, (timestamp, value))` tuples pickled with protocol 2, behind a four byte
+// big-endian length header
+fn pickle_message(metrics: &[(String, f64, f64)]) -> Vec<u8> {
+    let mut pickle = vec![0x80, 2, b']', b'('];
+    for &(ref path, timestamp, value) in metrics {
+        pickle.push(b'X');
+        pickle.extend_from_slice(&(path.len() as u32).to_le_bytes());
+        pickle.extend_from_slice(path.as_bytes());
+        pickle.push(b'G');
+        pickle.extend_from_slice(&timestamp.to_bits().to_be_bytes());
+        pickle.push(b'G');
+        pickle.extend_from_slice(&value.to_bits().to_be_bytes());
+        // Two TUPLE2s make `(timestamp, value)` and then `(path, (timestamp, value))`
+        pickle.extend_from_slice(&[0x86, 0x86]);
+    }
+    // APPENDS everything since the mark onto the list, then STOP
+    pickle.extend_from_slice(b"e.");
+    let mut message = (pickle.len() as u32).to_be_bytes().to_vec();
+    message.extend(pickle);
+    message
+}
+
+#[test]
+fn graphite_path_fills_in_host_name_and_tags() {
+    let measurement = Measurement::new("drive.free_bytes", 1.0, MetricKind::Gauge, Unit::Bytes)
+        .with_tag(HOST_TAG, "web-1")
+        .with_name_tag("path", "/var")
+        .with_tag("environment", "prod");
+    assert_eq!(graphite_path(DEFAULT_PATH_TEMPLATE, &measurement), "web-1.drive./var.free_bytes");
+    assert_eq!(graphite_path("servers.{environment}.{host}.{missing}.cpu", &measurement), "servers.prod.web-1._.cpu");
+}
+
+#[test]
+fn pickle_message_encodes_list_of_tuples() {
+    let message = pickle_message(&[("a.b".to_string(), 1.0, 2.0)]);
+    let mut expected = vec![0, 0, 0, 34, 0x80, 2, b']', b'(', b'X', 3, 0, 0, 0, b'a', b'.', b'b', b'G'];
+    expected.extend_from_slice(&[0x3f, 0xf0, 0, 0, 0, 0, 0, 0, b'G', 0x40, 0, 0, 0, 0, 0, 0, 0]);
+    expected.extend_from_slice(&[0x86, 0x86, b'e', b'.']);
+    assert_eq!(message, expected);
+}
+
+#[test]
+fn send_writes_plaintext_lines_over_tcp() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let mut output = GraphiteOutput::new(&address, DEFAULT_PATH_TEMPLATE, GraphiteProtocol::Plaintext);
+    let mut measurement = Measurement::new("physical_memory.available_bytes", 2048.0, MetricKind::Gauge, Unit::Bytes)
+        .with_tag(HOST_TAG, "web-1");
+    measurement.timestamp = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+    output.send(&[measurement]).unwrap();
+    drop(output);
+    let mut received = String::new();
+    listener.accept().unwrap().0.read_to_string(&mut received).unwrap();
+    assert_eq!(received, "web-1.physical_memory.available_bytes 2048 1500000000\n");
+}
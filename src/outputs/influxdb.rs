@@ -0,0 +1,245 @@
+use super::Output;
+use http::{self, Url};
+use measurement::{Measurement, MetricKind};
+#[cfg(test)]
+use measurement::Unit;
+use self_metrics;
+use std::collections::BTreeMap;
+use std::io::{Error, Result};
+#[cfg(test)]
+use std::io::{Read, Write};
+use std::net::UdpSocket;
+#[cfg(test)]
+use std::net::TcpListener;
+#[cfg(test)]
+use std::sync::mpsc;
+#[cfg(test)]
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+static HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_ATTEMPTS: u32 = 3;
+const MAX_LINES_PER_REQUEST: usize = 5000;
+// Keeps datagrams inside a typical MTU so they aren't fragmented
+const MAX_DATAGRAM_BYTES: usize = 1400;
+static DEFAULT_FIELD: &str = "value";
+
+enum Transport {
+    Udp(UdpSocket),
+    Http { url: Url, token: Option<String> },
+}
+
+/// Writes measurements to InfluxDB in line protocol, either as UDP datagrams or HTTP POSTs to
+/// the 1.x `/write` or 2.x `/api/v2/write` endpoint
+pub struct InfluxOutput {
+    address: String,
+    transport: Transport,
+}
+
+impl InfluxOutput {
+    /// Creates an output for a `udp://host:port` address or a full `http://` write URL including
+    /// its query, like `http://influx.home:8086/write?db=lines`. The token is only used over HTTP.
+    pub fn new(address: &str, token: Option<&str>) -> Result<InfluxOutput> {
+        let transport = if let Some(udp_address) = address.strip_prefix("udp://") {
+            let socket = UdpSocket::bind("0.0.0.0:0")?;
+            socket.connect(udp_address).map_err(|e| {
+                Error::new(e.kind(), format!("Error resolving InfluxDB address {}: {}", address, e))
+            })?;
+            Transport::Udp(socket)
+        } else {
+            Transport::Http { url: http::parse_url(address)?, token: token.map(|token| token.to_string()) }
+        };
+        Ok(InfluxOutput { address: address.to_string(), transport })
+    }
+}
+
+impl Output for InfluxOutput {
+    fn send(&mut self, measurements: &[Measurement]) -> Result<()> {
+        let lines: Vec<String> = group_points(measurements).iter().map(format_line).collect();
+        let result = match self.transport {
+            Transport::Udp(ref socket) => send_datagrams(socket, &lines),
+            Transport::Http { ref url, ref token } => {
+                lines.chunks(MAX_LINES_PER_REQUEST).try_for_each(|chunk| post_lines(url, token, chunk))
+            }
+        };
+        result.map_err(|e| {
+            self_metrics::count("influxdb.send_errors", 1);
+            Error::new(e.kind(), format!("Error sending {} lines to InfluxDB at {}: {}", lines.len(), self.address, e))
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Point {
+    measurement: String,
+    tags: BTreeMap<String, String>,
+    fields: Vec<(String, f64)>,
+    timestamp: SystemTime,
+    // Timers are individual samples rather than the latest level, so each gets a point of its own
+    mergeable: bool,
+}
+
+// Measurements whose names share everything but the last part, and that have the same tags and
+// timestamp, become fields of one point, so `drive.total_bytes` and `drive.free_bytes` for a
+// mount end up on one `drive` line. A field that the point already has starts a new point, since
+// InfluxDB would only keep one of the values.
+fn group_points(measurements: &[Measurement]) -> Vec<Point> {
+    let mut points: Vec<Point> = Vec::new();
+    for measurement in measurements {
+        if !measurement.value.is_finite() {
+            warn!("Not sending non-finite value {} for metric {}", measurement.value, measurement.name);
+            continue;
+        }
+        let (name, field) = match measurement.name.rfind('.') {
+            Some(index) => (&measurement.name[..index], &measurement.name[index + 1..]),
+            None => (measurement.name.as_str(), DEFAULT_FIELD),
+        };
+        let mergeable = measurement.kind != MetricKind::Timer;
+        let existing = points.iter().position(|point| {
+            mergeable && point.mergeable &&
+                point.measurement == name &&
+                point.tags == measurement.tags &&
+                point.timestamp == measurement.timestamp &&
+                !point.fields.iter().any(|(key, _)| key == field)
+        });
+        match existing {
+            Some(index) => points[index].fields.push((field.to_string(), measurement.value)),
+            None => points.push(Point {
+                measurement: name.to_string(),
+                tags: measurement.tags.clone(),
+                fields: vec![(field.to_string(), measurement.value)],
+                timestamp: measurement.timestamp,
+                mergeable,
+            }),
+        }
+    }
+    points
+}
+
+fn format_line(point: &Point) -> String {
+    let mut line = escape(&point.measurement, &[',', ' ']);
+    for (key, value) in &point.tags {
+        if value.is_empty() {
+            continue;
+        }
+        line.push_str(&format!(",{}={}", escape(key, &[',', '=', ' ']), escape(value, &[',', '=', ' '])));
+    }
+    let fields: Vec<String> = point.fields
+        .iter()
+        .map(|&(ref key, value)| format!("{}={}", escape(key, &[',', '=', ' ']), value))
+        .collect();
+    let since_epoch = point.timestamp.duration_since(UNIX_EPOCH).unwrap_or_else(|_| Duration::from_secs(0));
+    let nanoseconds = since_epoch.as_secs() * 1_000_000_000 + since_epoch.subsec_nanos() as u64;
+    line + " " + &fields.join(",") + " " + &nanoseconds.to_string()
+}
+
+fn escape(text: &str, special: &[char]) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        if c == '\\' || special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn send_datagrams(socket: &UdpSocket, lines: &[String]) -> Result<()> {
+    let mut datagram = String::new();
+    for line in lines {
+        if !datagram.is_empty() && datagram.len() + line.len() + 1 > MAX_DATAGRAM_BYTES {
+            socket.send(datagram.as_bytes())?;
+            datagram.clear();
+        }
+        datagram.push_str(line);
+        datagram.push('\n');
+    }
+    if !datagram.is_empty() {
+        socket.send(datagram.as_bytes())?;
+    }
+    Ok(())
+}
+
+fn post_lines(url: &Url, token: &Option<String>, lines: &[String]) -> Result<()> {
+    let body = lines.join("\n") + "\n";
+    let authorization = token.as_ref().map(|token| "Token ".to_string() + token);
+    let headers: Vec<(&str, &str)> = authorization.iter().map(|value| ("Authorization", value.as_str())).collect();
+    http::post_with_retry(url, "text/plain; charset=utf-8", &headers, body.as_bytes(), HTTP_TIMEOUT, MAX_ATTEMPTS,
+                          "influxdb.retries")
+}
+
+#[test]
+fn format_line_groups_fields_and_escapes_tags() {
+    let mut total = Measurement::new("drive.total_bytes", 4096.0, MetricKind::Gauge, Unit::Bytes)
+        .with_tag("mount", "/mnt/my disk")
+        .with_tag("host", "web-1");
+    total.timestamp = UNIX_EPOCH + Duration::new(1_500_000_000, 5);
+    let mut free = Measurement::new("drive.free_bytes", 1024.5, MetricKind::Gauge, Unit::Bytes)
+        .with_tag("mount", "/mnt/my disk")
+        .with_tag("host", "web-1");
+    free.timestamp = total.timestamp;
+    let other_mount = free.clone().with_tag("mount", "/");
+    let points = group_points(&[total, free, other_mount]);
+    assert_eq!(points.len(), 2);
+    assert_eq!(format_line(&points[0]),
+               "drive,host=web-1,mount=/mnt/my\\ disk total_bytes=4096,free_bytes=1024.5 1500000000000000005");
+}
+
+#[test]
+fn group_points_keeps_every_timer_sample() {
+    let mut first = Measurement::new("lines_agent.output.send_time", 12.0, MetricKind::Timer, Unit::Milliseconds)
+        .with_tag("output", "statsd");
+    first.timestamp = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+    let mut second = first.clone();
+    second.value = 30.0;
+    second.timestamp += Duration::from_millis(1);
+    let points = group_points(&[first, second]);
+    assert_eq!(points.iter().map(format_line).collect::<Vec<_>>(), vec![
+        "lines_agent.output,output=statsd send_time=12 1500000000000000000",
+        "lines_agent.output,output=statsd send_time=30 1500000000001000000",
+    ]);
+}
+
+// Reads one request from a stand-in server's connection, going by its Content-Length
+#[cfg(test)]
+fn read_test_request(stream: &mut ::std::net::TcpStream) -> String {
+    let mut request = Vec::new();
+    let mut buffer = [0; 4096];
+    loop {
+        let read = stream.read(&mut buffer).unwrap();
+        request.extend_from_slice(&buffer[..read]);
+        let text = String::from_utf8_lossy(&request).into_owned();
+        if let Some(end_of_headers) = text.find("\r\n\r\n") {
+            let content_length: usize = text.lines()
+                .find(|line| line.starts_with("Content-Length: "))
+                .map(|line| line["Content-Length: ".len()..].parse().unwrap())
+                .unwrap_or(0);
+            if read == 0 || text.len() >= end_of_headers + 4 + content_length {
+                return text;
+            }
+        }
+    }
+}
+
+#[test]
+fn send_retries_server_errors_over_http() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/api/v2/write?org=home&bucket=lines", listener.local_addr().unwrap());
+    let (request_sender, request_receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for status in &["503 Service Unavailable", "204 No Content"] {
+            let mut stream = listener.accept().unwrap().0;
+            request_sender.send(read_test_request(&mut stream)).unwrap();
+            write!(stream, "HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).unwrap();
+        }
+    });
+    let mut output = InfluxOutput::new(&url, Some("secret")).unwrap();
+    let mut measurement = Measurement::new("cpu_time.busy_time", 12.0, MetricKind::Gauge, Unit::Percent);
+    measurement.timestamp = UNIX_EPOCH + Duration::from_secs(1);
+    output.send(&[measurement]).unwrap();
+    let requests: Vec<String> = request_receiver.iter().collect();
+    assert_eq!(requests.len(), 2);
+    assert!(requests[1].starts_with("POST /api/v2/write?org=home&bucket=lines HTTP/1.1\r\n"));
+    assert!(requests[1].contains("\r\nAuthorization: Token secret\r\n"));
+    assert!(requests[1].ends_with("\r\n\r\ncpu_time busy_time=12 1000000000\n"));
+}
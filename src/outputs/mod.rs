@@ -0,0 +1,140 @@
+pub mod fanout;
+pub mod file;
+pub mod graphite;
+pub mod influxdb;
+pub mod otlp;
+pub mod print;
+pub mod prometheus;
+pub mod registry;
+pub mod spool;
+pub mod statsd;
+pub mod statsd_sinks;
+
+use measurement::Measurement;
+#[cfg(test)]
+use measurement::{MetricKind, Unit};
+use self_metrics;
+use std::io::{Result, Write};
+#[cfg(test)]
+use std::time::{Duration, UNIX_EPOCH};
+
+pub type FileFormat = self::file::FileFormat;
+pub type FileOutput = self::file::FileOutput;
+pub type GraphiteOutput = self::graphite::GraphiteOutput;
+pub type GraphiteProtocol = self::graphite::GraphiteProtocol;
+pub type InfluxOutput = self::influxdb::InfluxOutput;
+pub type OtlpEncoding = self::otlp::OtlpEncoding;
+pub type OtlpOutput = self::otlp::OtlpOutput;
+pub type OutputConfig = self::registry::OutputConfig;
+pub type OutputWorker = self::fanout::OutputWorker;
+pub type PrintFormat = self::print::PrintFormat;
+pub type PrintOutput = self::print::PrintOutput;
+pub type PrometheusOutput = self::prometheus::PrometheusOutput;
+pub type StatsdOutput = self::statsd::StatsdOutput;
+pub type StatsdProtocol = self::statsd::StatsdProtocol;
+
+/// Somewhere that measurements get shipped to once sensors have produced them
+pub trait Output: Send {
+    fn send(&mut self, measurements: &[Measurement]) -> Result<()>;
+}
+
+/// Writes to a stream connection, connecting first if there isn't one. A connection the other end
+/// has closed often only shows up as an error on the next write, so a failed write is retried
+/// once on a new connection, counted in the named self-metric, before giving up with its error.
+pub fn write_reconnecting<S, F>(stream: &mut Option<S>, connect: F, data: &[u8], destination: &str,
+                                reconnects_metric: &str) -> Result<()>
+where
+    S: Write,
+    F: Fn() -> Result<S>,
+{
+    if stream.is_none() {
+        *stream = Some(connect()?);
+    }
+    match stream.as_mut().unwrap().write_all(data) {
+        Ok(()) => return Ok(()),
+        Err(e) => {
+            *stream = None;
+            debug!("Error writing to {}, reconnecting: {}", destination, e);
+        }
+    }
+    self_metrics::count(reconnects_metric, 1);
+    let mut new_stream = connect()?;
+    new_stream.write_all(data)?;
+    *stream = Some(new_stream);
+    Ok(())
+}
+
+/// The tag every measurement carries to say which machine it came from
+pub const HOST_TAG: &str = "host";
+
+/// Folds the name tags into a dotted name for protocols that have no notion of tags, with their
+/// values just before the last part of the metric name, which gives the same names these
+/// protocols have always been sent. Other tags are left out so that adding one doesn't rename
+/// every metric.
+pub fn flatten_name(measurement: &Measurement) -> String {
+    let tag_values: Vec<String> = measurement.name_tags
+        .iter()
+        .filter_map(|key| measurement.tags.get(key))
+        .map(|value| name_part(value))
+        .collect();
+    if tag_values.is_empty() {
+        return measurement.name.clone();
+    }
+    match measurement.name.rfind('.') {
+        Some(index) => {
+            let (head, leaf) = measurement.name.split_at(index);
+            head.to_string() + "." + &tag_values.join(".") + leaf
+        },
+        None => measurement.name.clone() + "." + &tag_values.join("."),
+    }
+}
+
+// Drive letters lose their colon and paths keep their slashes, as they always have in drive
+// names. Anything that would split a statsd or Graphite line is replaced.
+fn name_part(value: &str) -> String {
+    let part: String = value
+        .chars()
+        .filter(|&c| c != ':')
+        .map(|c| if c.is_whitespace() || c == '|' || c == '@' || c == '#' { '_' } else { c })
+        .collect();
+    if part.is_empty() { "_".to_string() } else { part }
+}
+
+// A measurement taken at a fixed time, so that tests can compare what outputs write
+#[cfg(test)]
+pub fn test_measurement(name: &str, value: f64, kind: MetricKind, unit: Unit) -> Measurement {
+    let mut measurement = Measurement::new(name, value, kind, unit);
+    measurement.timestamp = UNIX_EPOCH + Duration::from_millis(1_500_000_000_250);
+    measurement
+}
+
+#[test]
+fn flatten_name_keeps_the_names_sent_before_tags() {
+    // Drives were `drive.<path>.<metric>` and CPU time had no CPU in its name
+    let drive = Measurement::new("drive.free_bytes", 1.0, MetricKind::Gauge, Unit::Bytes)
+        .with_name_tag("path", "/var")
+        .with_tag("mount", "/var")
+        .with_tag("device", "/dev/sda1")
+        .with_tag(HOST_TAG, "web-1")
+        .with_tag("environment", "production")
+        .with_tag("role", "web");
+    assert_eq!(flatten_name(&drive), "drive./var.free_bytes");
+    let windows_drive = Measurement::new("drive.total_bytes", 1.0, MetricKind::Gauge, Unit::Bytes)
+        .with_name_tag("mount", "C:");
+    assert_eq!(flatten_name(&windows_drive), "drive.C.total_bytes");
+    let cpu = Measurement::new("cpu_time.busy_time", 1.0, MetricKind::Gauge, Unit::Percent)
+        .with_tag("cpu", "total")
+        .with_tag("environment", "production");
+    assert_eq!(flatten_name(&cpu), "cpu_time.busy_time");
+    let memory = Measurement::new("physical_memory.free_bytes", 1.0, MetricKind::Gauge, Unit::Bytes)
+        .with_tag(HOST_TAG, "web-1");
+    assert_eq!(flatten_name(&memory), "physical_memory.free_bytes");
+}
+
+#[test]
+fn flatten_name_puts_name_tags_before_last_part_in_order() {
+    let measurement = Measurement::new("cpu_time.mode_time", 1.0, MetricKind::Gauge, Unit::Percent)
+        .with_name_tag("cpu", "3")
+        .with_name_tag("mode", "my mode");
+    assert_eq!(flatten_name(&measurement), "cpu_time.3.my_mode.mode_time");
+}
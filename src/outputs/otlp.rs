@@ -0,0 +1,563 @@
+extern crate serde_json;
+
+use super::{Output, HOST_TAG};
+#[cfg(test)]
+use super::test_measurement;
+use http::{self, Url};
+use measurement::{Measurement, MetricKind, Unit};
+use self_metrics;
+use serde::Serializer;
+use std::collections::BTreeMap;
+use std::env;
+use std::io::{Error, Result};
+#[cfg(test)]
+use std::io::{Read, Write};
+#[cfg(test)]
+use std::net::TcpListener;
+#[cfg(test)]
+use std::sync::mpsc;
+#[cfg(test)]
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+static HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_ATTEMPTS: u32 = 5;
+static SERVICE_NAME: &str = "lines-agent";
+static VERSION: &str = env!("CARGO_PKG_VERSION");
+// Aggregation temporality for sums whose points each cover the time since the previous point
+const AGGREGATION_TEMPORALITY_DELTA: u32 = 1;
+
+/// How OTLP payloads are encoded on the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OtlpEncoding {
+    #[default]
+    Protobuf,
+    Json,
+}
+
+type Tags = BTreeMap<String, String>;
+
+/// Exports measurements to an OpenTelemetry collector over OTLP/HTTP, as gauges, delta sums for
+/// counters and summaries for timings
+pub struct OtlpOutput {
+    endpoint: String,
+    url: Url,
+    encoding: OtlpEncoding,
+    resource: Resource,
+    // When each counter series was last exported successfully, which is where its next delta starts
+    series_starts: BTreeMap<(String, Tags), u64>,
+    created_at: u64,
+}
+
+impl OtlpOutput {
+    /// Creates an exporter posting to a full endpoint URL, normally ending in `/v1/metrics`
+    pub fn new(endpoint: &str, encoding: OtlpEncoding, hostname: &str) -> Result<OtlpOutput> {
+        let resource = Resource {
+            attributes: vec![
+                KeyValue::new("host.name", hostname),
+                KeyValue::new("os.type", env::consts::OS),
+                KeyValue::new("service.name", SERVICE_NAME),
+                KeyValue::new("service.version", VERSION),
+            ],
+        };
+        Ok(OtlpOutput {
+            endpoint: endpoint.to_string(),
+            url: http::parse_url(endpoint)?,
+            encoding,
+            resource,
+            series_starts: BTreeMap::new(),
+            created_at: unix_nanos(SystemTime::now()),
+        })
+    }
+
+    // Also returns where each counter series in the request ends, to become its next start once
+    // the request has been sent
+    fn build_request(&self, measurements: &[Measurement]) -> (ExportMetricsServiceRequest, BTreeMap<(String, Tags), u64>) {
+        let mut metrics: Vec<Metric> = Vec::new();
+        let mut series_ends = BTreeMap::new();
+        for measurement in measurements {
+            if !measurement.value.is_finite() {
+                warn!("Not exporting non-finite value {} for metric {}", measurement.value, measurement.name);
+                continue;
+            }
+            if measurement.kind == MetricKind::Set {
+                debug!("Not exporting set metric {}, OTLP has no equivalent", measurement.name);
+                continue;
+            }
+            let time = unix_nanos(measurement.timestamp);
+            // The host is already on the resource
+            let attributes: Vec<KeyValue> = measurement.tags
+                .iter()
+                .filter(|&(key, _)| key != HOST_TAG)
+                .map(|(key, value)| KeyValue::new(key, value))
+                .collect();
+            let existing = metrics
+                .iter()
+                .position(|metric| metric.name == measurement.name && metric.kind == measurement.kind);
+            let index = match existing {
+                Some(index) => index,
+                None => {
+                    metrics.push(Metric::new(&measurement.name, measurement.kind, measurement.unit));
+                    metrics.len() - 1
+                }
+            };
+            let metric = &mut metrics[index];
+            if let Some(ref mut gauge) = metric.gauge {
+                gauge.data_points.push(NumberDataPoint {
+                    attributes,
+                    start_time_unix_nano: 0,
+                    time_unix_nano: time,
+                    as_double: measurement.value,
+                });
+            } else if let Some(ref mut sum) = metric.sum {
+                let series = (measurement.name.clone(), measurement.tags.clone());
+                let start = match series_ends.insert(series.clone(), time) {
+                    Some(previous_end) => previous_end,
+                    None => self.series_starts.get(&series).cloned().unwrap_or(self.created_at),
+                };
+                sum.data_points.push(NumberDataPoint {
+                    attributes,
+                    start_time_unix_nano: start,
+                    time_unix_nano: time,
+                    as_double: measurement.value,
+                });
+            } else if let Some(ref mut summary) = metric.summary {
+                summary.data_points.push(SummaryDataPoint {
+                    attributes,
+                    time_unix_nano: time,
+                    count: 1,
+                    sum: measurement.value,
+                });
+            }
+        }
+        let request = ExportMetricsServiceRequest {
+            resource_metrics: vec![ResourceMetrics {
+                resource: self.resource.clone(),
+                scope_metrics: vec![ScopeMetrics {
+                    scope: InstrumentationScope { name: SERVICE_NAME.to_string(), version: VERSION.to_string() },
+                    metrics,
+                }],
+            }],
+        };
+        (request, series_ends)
+    }
+}
+
+impl Output for OtlpOutput {
+    fn send(&mut self, measurements: &[Measurement]) -> Result<()> {
+        let (request, series_ends) = self.build_request(measurements);
+        let (content_type, body) = match self.encoding {
+            OtlpEncoding::Protobuf => {
+                let mut body = Vec::new();
+                request.encode(&mut body);
+                ("application/x-protobuf", body)
+            },
+            OtlpEncoding::Json => ("application/json", serde_json::to_vec(&request).map_err(Error::other)?),
+        };
+        let result = http::post_with_retry(&self.url, content_type, &[], &body, HTTP_TIMEOUT, MAX_ATTEMPTS, "otlp.retries");
+        result.map_err(|e| {
+            self_metrics::count("otlp.send_errors", 1);
+            Error::new(e.kind(), format!("Error exporting {} measurements to OTLP endpoint {}: {}",
+                                         measurements.len(), self.endpoint, e))
+        })?;
+        // A failed export is spooled and sent again later, when its deltas still have to start
+        // where the last export that got through ended
+        self.series_starts.extend(series_ends);
+        Ok(())
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> u64 {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_else(|_| Duration::from_secs(0));
+    since_epoch.as_secs() * 1_000_000_000 + since_epoch.subsec_nanos() as u64
+}
+
+// The OTLP metrics data model, serialized to the OTLP JSON mapping with serde and to protobuf by
+// hand below. Only the fields the agent uses are included.
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportMetricsServiceRequest {
+    resource_metrics: Vec<ResourceMetrics>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ResourceMetrics {
+    resource: Resource,
+    scope_metrics: Vec<ScopeMetrics>,
+}
+
+#[derive(Clone, Serialize)]
+struct Resource {
+    attributes: Vec<KeyValue>,
+}
+
+#[derive(Clone, Serialize)]
+struct KeyValue {
+    key: String,
+    value: AnyValue,
+}
+
+impl KeyValue {
+    fn new(key: &str, value: &str) -> KeyValue {
+        KeyValue { key: key.to_string(), value: AnyValue { string_value: value.to_string() } }
+    }
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AnyValue {
+    string_value: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ScopeMetrics {
+    scope: InstrumentationScope,
+    metrics: Vec<Metric>,
+}
+
+#[derive(Serialize)]
+struct InstrumentationScope {
+    name: String,
+    version: String,
+}
+
+#[derive(Serialize)]
+struct Metric {
+    name: String,
+    unit: String,
+    #[serde(skip)]
+    kind: MetricKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gauge: Option<Gauge>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sum: Option<Sum>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    summary: Option<Summary>,
+}
+
+impl Metric {
+    fn new(name: &str, kind: MetricKind, unit: Unit) -> Metric {
+        let unit = match unit {
+            Unit::Bytes => "By",
+            Unit::Percent => "%",
+            Unit::Milliseconds => "ms",
+            Unit::None => "",
+        };
+        let mut metric = Metric { name: name.to_string(), unit: unit.to_string(), kind, gauge: None, sum: None, summary: None };
+        match kind {
+            MetricKind::Gauge | MetricKind::Set => metric.gauge = Some(Gauge { data_points: Vec::new() }),
+            MetricKind::Counter => metric.sum = Some(Sum {
+                data_points: Vec::new(),
+                aggregation_temporality: AGGREGATION_TEMPORALITY_DELTA,
+                is_monotonic: true,
+            }),
+            MetricKind::Timer | MetricKind::Histogram | MetricKind::Distribution =>
+                metric.summary = Some(Summary { data_points: Vec::new() }),
+        }
+        metric
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Gauge {
+    data_points: Vec<NumberDataPoint>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Sum {
+    data_points: Vec<NumberDataPoint>,
+    aggregation_temporality: u32,
+    is_monotonic: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Summary {
+    data_points: Vec<SummaryDataPoint>,
+}
+
+// 64 bit integers are strings in OTLP JSON, since JavaScript can't represent all of them
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct NumberDataPoint {
+    attributes: Vec<KeyValue>,
+    #[serde(serialize_with = "serialize_as_string", skip_serializing_if = "is_zero")]
+    start_time_unix_nano: u64,
+    #[serde(serialize_with = "serialize_as_string")]
+    time_unix_nano: u64,
+    as_double: f64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SummaryDataPoint {
+    attributes: Vec<KeyValue>,
+    #[serde(serialize_with = "serialize_as_string")]
+    time_unix_nano: u64,
+    #[serde(serialize_with = "serialize_as_string")]
+    count: u64,
+    sum: f64,
+}
+
+fn serialize_as_string<S>(value: &u64, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(&value.to_string())
+}
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
+
+// Protobuf wire types
+const VARINT: u8 = 0;
+const FIXED64: u8 = 1;
+const LENGTH_DELIMITED: u8 = 2;
+
+trait Encode {
+    fn encode(&self, buffer: &mut Vec<u8>);
+}
+
+fn put_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn put_key(buffer: &mut Vec<u8>, field: u32, wire_type: u8) {
+    put_varint(buffer, (u64::from(field) << 3) | u64::from(wire_type));
+}
+
+fn put_bytes(buffer: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    put_key(buffer, field, LENGTH_DELIMITED);
+    put_varint(buffer, bytes.len() as u64);
+    buffer.extend_from_slice(bytes);
+}
+
+fn put_message<T: Encode>(buffer: &mut Vec<u8>, field: u32, message: &T) {
+    let mut encoded = Vec::new();
+    message.encode(&mut encoded);
+    put_bytes(buffer, field, &encoded);
+}
+
+fn put_fixed64(buffer: &mut Vec<u8>, field: u32, value: u64) {
+    put_key(buffer, field, FIXED64);
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+impl Encode for ExportMetricsServiceRequest {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        for resource_metrics in &self.resource_metrics {
+            put_message(buffer, 1, resource_metrics);
+        }
+    }
+}
+
+impl Encode for ResourceMetrics {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        put_message(buffer, 1, &self.resource);
+        for scope_metrics in &self.scope_metrics {
+            put_message(buffer, 2, scope_metrics);
+        }
+    }
+}
+
+impl Encode for Resource {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        for attribute in &self.attributes {
+            put_message(buffer, 1, attribute);
+        }
+    }
+}
+
+impl Encode for KeyValue {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        put_bytes(buffer, 1, self.key.as_bytes());
+        put_message(buffer, 2, &self.value);
+    }
+}
+
+impl Encode for AnyValue {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        put_bytes(buffer, 1, self.string_value.as_bytes());
+    }
+}
+
+impl Encode for ScopeMetrics {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        put_message(buffer, 1, &self.scope);
+        for metric in &self.metrics {
+            put_message(buffer, 2, metric);
+        }
+    }
+}
+
+impl Encode for InstrumentationScope {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        put_bytes(buffer, 1, self.name.as_bytes());
+        put_bytes(buffer, 2, self.version.as_bytes());
+    }
+}
+
+impl Encode for Metric {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        put_bytes(buffer, 1, self.name.as_bytes());
+        put_bytes(buffer, 3, self.unit.as_bytes());
+        if let Some(ref gauge) = self.gauge {
+            put_message(buffer, 5, gauge);
+        }
+        if let Some(ref sum) = self.sum {
+            put_message(buffer, 7, sum);
+        }
+        if let Some(ref summary) = self.summary {
+            put_message(buffer, 11, summary);
+        }
+    }
+}
+
+impl Encode for Gauge {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        for data_point in &self.data_points {
+            put_message(buffer, 1, data_point);
+        }
+    }
+}
+
+impl Encode for Sum {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        for data_point in &self.data_points {
+            put_message(buffer, 1, data_point);
+        }
+        put_key(buffer, 2, VARINT);
+        put_varint(buffer, u64::from(self.aggregation_temporality));
+        put_key(buffer, 3, VARINT);
+        put_varint(buffer, self.is_monotonic as u64);
+    }
+}
+
+impl Encode for Summary {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        for data_point in &self.data_points {
+            put_message(buffer, 1, data_point);
+        }
+    }
+}
+
+impl Encode for NumberDataPoint {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        if self.start_time_unix_nano != 0 {
+            put_fixed64(buffer, 2, self.start_time_unix_nano);
+        }
+        put_fixed64(buffer, 3, self.time_unix_nano);
+        put_fixed64(buffer, 4, self.as_double.to_bits());
+        for attribute in &self.attributes {
+            put_message(buffer, 7, attribute);
+        }
+    }
+}
+
+impl Encode for SummaryDataPoint {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        put_fixed64(buffer, 3, self.time_unix_nano);
+        put_fixed64(buffer, 4, self.count);
+        put_fixed64(buffer, 5, self.sum.to_bits());
+        for attribute in &self.attributes {
+            put_message(buffer, 7, attribute);
+        }
+    }
+}
+
+#[test]
+fn build_request_maps_gauges_and_counters_to_json() {
+    let mut output = OtlpOutput::new("http://localhost:4318/v1/metrics", OtlpEncoding::Json, "web-1").unwrap();
+    output.created_at = 1_000_000_000;
+    let (request, _) = output.build_request(&[
+        test_measurement("drive.free_bytes", 512.0, MetricKind::Gauge, Unit::Bytes).with_tag("mount", "/"),
+        test_measurement("lines_agent.sensor.errors", 3.0, MetricKind::Counter, Unit::None),
+    ]);
+    let json: serde_json::Value = serde_json::to_value(&request).unwrap();
+    let scope_metrics = &json["resourceMetrics"][0]["scopeMetrics"][0];
+    let gauge = &scope_metrics["metrics"][0];
+    assert_eq!(gauge["name"], "drive.free_bytes");
+    assert_eq!(gauge["unit"], "By");
+    assert_eq!(gauge["gauge"]["dataPoints"][0]["asDouble"], 512.0);
+    assert_eq!(gauge["gauge"]["dataPoints"][0]["timeUnixNano"], "1500000000250000000");
+    assert_eq!(gauge["gauge"]["dataPoints"][0]["attributes"][0]["key"], "mount");
+    let sum = &scope_metrics["metrics"][1]["sum"];
+    assert_eq!(sum["aggregationTemporality"], 1);
+    assert_eq!(sum["isMonotonic"], true);
+    assert_eq!(sum["dataPoints"][0]["startTimeUnixNano"], "1000000000");
+    assert_eq!(json["resourceMetrics"][0]["resource"]["attributes"][0]["value"]["stringValue"], "web-1");
+}
+
+#[test]
+fn encode_writes_protobuf_fields() {
+    let data_point = NumberDataPoint {
+        attributes: vec![KeyValue::new("cpu", "0")],
+        start_time_unix_nano: 0,
+        time_unix_nano: 1,
+        as_double: 1.0,
+    };
+    let mut buffer = Vec::new();
+    data_point.encode(&mut buffer);
+    let mut expected = vec![0x19, 1, 0, 0, 0, 0, 0, 0, 0, 0x21, 0, 0, 0, 0, 0, 0, 0xf0, 0x3f];
+    expected.extend_from_slice(&[0x3a, 10, 0x0a, 3, b'c', b'p', b'u', 0x12, 3, 0x0a, 1, b'0']);
+    assert_eq!(buffer, expected);
+}
+
+#[test]
+fn send_posts_protobuf_to_endpoint() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}/v1/metrics", listener.local_addr().unwrap());
+    let (request_sender, request_receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut stream = listener.accept().unwrap().0;
+        let mut request = Vec::new();
+        let mut buffer = [0; 4096];
+        while !String::from_utf8_lossy(&request).contains("\r\n\r\n") {
+            let read = stream.read(&mut buffer).unwrap();
+            request.extend_from_slice(&buffer[..read]);
+        }
+        write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+        request_sender.send(String::from_utf8_lossy(&request).into_owned()).unwrap();
+    });
+    let mut output = OtlpOutput::new(&endpoint, OtlpEncoding::Protobuf, "web-1").unwrap();
+    output.send(&[test_measurement("cpu_time.busy_time", 12.0, MetricKind::Gauge, Unit::Percent)]).unwrap();
+    let request = request_receiver.recv().unwrap();
+    assert!(request.starts_with("POST /v1/metrics HTTP/1.1\r\n"));
+    assert!(request.contains("\r\nContent-Type: application/x-protobuf\r\n"));
+}
+
+#[test]
+fn send_moves_delta_starts_on_only_after_success() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}/v1/metrics", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        // Rejected outright first so that the export fails without being retried
+        for status in &["400 Bad Request", "200 OK"] {
+            let mut stream = listener.accept().unwrap().0;
+            let mut request = Vec::new();
+            let mut buffer = [0; 4096];
+            while !String::from_utf8_lossy(&request).contains("\r\n\r\n") {
+                let read = stream.read(&mut buffer).unwrap();
+                request.extend_from_slice(&buffer[..read]);
+            }
+            write!(stream, "HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).unwrap();
+        }
+    });
+    let mut output = OtlpOutput::new(&endpoint, OtlpEncoding::Protobuf, "web-1").unwrap();
+    let counters = [test_measurement("lines_agent.sensor.errors", 3.0, MetricKind::Counter, Unit::None)];
+    assert!(output.send(&counters).is_err());
+    assert!(output.series_starts.is_empty());
+    output.send(&counters).unwrap();
+    assert_eq!(output.series_starts.values().collect::<Vec<_>>(), vec![&1_500_000_000_250_000_000]);
+}
@@ -0,0 +1,109 @@
+use super::Output;
+use super::file::{format_json_line, kind_name, unit_name};
+use measurement::Measurement;
+#[cfg(test)]
+use measurement::{MetricKind, Unit};
+use std::io::{self, Result, Write};
+use std::str::FromStr;
+
+/// How measurements are shown when printing them rather than sending them anywhere
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrintFormat {
+    /// Aligned columns for reading
+    Table,
+    /// A JSON object per line, the same as the file output writes, for scripts
+    Json,
+}
+
+impl FromStr for PrintFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> ::std::result::Result<PrintFormat, String> {
+        match format {
+            "table" => Ok(PrintFormat::Table),
+            "json" => Ok(PrintFormat::Json),
+            other => Err(format!("Unknown format '{}', expected table or json", other)),
+        }
+    }
+}
+
+/// Writes measurements to stdout, so that a host's sensors can be checked without sending
+/// anything to the real outputs
+pub struct PrintOutput {
+    format: PrintFormat,
+    writer: Box<dyn Write + Send>,
+}
+
+impl PrintOutput {
+    pub fn new(format: PrintFormat, writer: Box<dyn Write + Send>) -> PrintOutput {
+        PrintOutput { format, writer }
+    }
+
+    pub fn stdout(format: PrintFormat) -> PrintOutput {
+        PrintOutput::new(format, Box::new(io::stdout()))
+    }
+}
+
+impl Output for PrintOutput {
+    fn send(&mut self, measurements: &[Measurement]) -> Result<()> {
+        match self.format {
+            PrintFormat::Table => self.writer.write_all(format_table(measurements).as_bytes())?,
+            PrintFormat::Json => {
+                for measurement in measurements {
+                    self.writer.write_all(format_json_line(measurement)?.as_bytes())?;
+                }
+            }
+        }
+        self.writer.flush()
+    }
+}
+
+static TABLE_HEADINGS: [&str; 5] = ["NAME", "VALUE", "UNIT", "KIND", "TAGS"];
+
+fn format_table(measurements: &[Measurement]) -> String {
+    let mut rows: Vec<[String; 5]> = vec![[
+        TABLE_HEADINGS[0].to_string(),
+        TABLE_HEADINGS[1].to_string(),
+        TABLE_HEADINGS[2].to_string(),
+        TABLE_HEADINGS[3].to_string(),
+        TABLE_HEADINGS[4].to_string(),
+    ]];
+    for measurement in measurements {
+        let tags: Vec<String> = measurement.tags.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+        rows.push([
+            measurement.name.clone(),
+            measurement.value.to_string(),
+            unit_name(measurement.unit).to_string(),
+            kind_name(measurement.kind).to_string(),
+            tags.join(","),
+        ]);
+    }
+    let mut widths = [0; 5];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let mut table = String::new();
+    for row in &rows {
+        let cells: Vec<String> = row.iter()
+            .zip(widths.iter())
+            .map(|(cell, &width)| format!("{:width$}", cell, width = width))
+            .collect();
+        table.push_str(cells.join("  ").trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+#[test]
+fn format_table_aligns_columns() {
+    let measurements = vec![
+        Measurement::new("drive.free_bytes", 1024.0, MetricKind::Gauge, Unit::Bytes).with_tag("mount", "/"),
+        Measurement::new("cpu_time.busy_time", 2.5, MetricKind::Gauge, Unit::Percent),
+    ];
+    assert_eq!(format_table(&measurements),
+               "NAME                VALUE  UNIT     KIND   TAGS\n\
+                drive.free_bytes    1024   bytes    gauge  mount=/\n\
+                cpu_time.busy_time  2.5    percent  gauge\n");
+}
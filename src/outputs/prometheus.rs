@@ -0,0 +1,267 @@
+use super::Output;
+use measurement::{Measurement, MetricKind, Unit};
+use self_metrics;
+use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
+use std::fmt::Write as FmtWrite;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+static CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+static METRICS_PATH: &str = "/metrics";
+// Scrapers that connect and then go quiet mustn't hold up the next scrape for long
+static CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_BYTES: usize = 8192;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Sample {
+    Value(f64),
+    Summary { sum: f64, count: u64 },
+}
+
+struct Family {
+    help: String,
+    family_type: &'static str,
+    kind: MetricKind,
+    // Keyed by the rendered label set, so each distinct set of tags is its own series
+    samples: BTreeMap<String, Sample>,
+}
+
+type Families = BTreeMap<String, Family>;
+
+/// Serves the latest value of every series over HTTP for Prometheus to scrape, rather than
+/// pushing anywhere. Sending only updates what the next scrape will see.
+pub struct PrometheusOutput {
+    families: Arc<Mutex<Families>>,
+    local_address: SocketAddr,
+}
+
+impl PrometheusOutput {
+    /// Starts serving `/metrics` on the address in the background
+    pub fn bind(address: &str) -> Result<PrometheusOutput> {
+        let listener = TcpListener::bind(address).map_err(|e| {
+            Error::new(e.kind(), format!("Error listening for Prometheus scrapes on {}: {}", address, e))
+        })?;
+        let local_address = listener.local_addr()?;
+        let families = Arc::new(Mutex::new(Families::new()));
+        let server_families = families.clone();
+        thread::Builder::new()
+            .name("prometheus-server".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    let result = stream.and_then(|stream| serve_connection(stream, &server_families));
+                    if let Err(e) = result {
+                        debug!("Error serving Prometheus scrape: {}", e);
+                    }
+                }
+            })?;
+        Ok(PrometheusOutput { families, local_address })
+    }
+
+    pub fn local_address(&self) -> SocketAddr {
+        self.local_address
+    }
+}
+
+impl Output for PrometheusOutput {
+    fn send(&mut self, measurements: &[Measurement]) -> Result<()> {
+        let mut families = self.families.lock().unwrap();
+        for measurement in measurements {
+            record(&mut families, measurement);
+        }
+        Ok(())
+    }
+}
+
+fn record(families: &mut Families, measurement: &Measurement) {
+    if !measurement.value.is_finite() {
+        warn!("Not exposing non-finite value {} for metric {}", measurement.value, measurement.name);
+        return;
+    }
+    let (family_type, suffix) = match measurement.kind {
+        MetricKind::Gauge => ("gauge", ""),
+        // Counters arrive as deltas, but Prometheus wants the running total
+        MetricKind::Counter => ("counter", "_total"),
+        MetricKind::Timer | MetricKind::Histogram | MetricKind::Distribution => ("summary", ""),
+        MetricKind::Set => {
+            debug!("Not exposing set metric {}, Prometheus has no equivalent", measurement.name);
+            return;
+        }
+    };
+    let name = metric_name(&measurement.name, measurement.unit) + suffix;
+    let family = match families.entry(name) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => entry.insert(Family {
+            help: help_text(measurement),
+            family_type,
+            kind: measurement.kind,
+            samples: BTreeMap::new(),
+        }),
+    };
+    if family.kind != measurement.kind {
+        warn!("Not exposing metric {} as a {:?}, it was already exposed as a {:?}",
+              measurement.name, measurement.kind, family.kind);
+        return;
+    }
+    // Prometheus units are seconds rather than milliseconds
+    let value = if measurement.unit == Unit::Milliseconds { measurement.value / 1000.0 } else { measurement.value };
+    let sample = family.samples.entry(render_labels(measurement)).or_insert(match family.family_type {
+        "summary" => Sample::Summary { sum: 0.0, count: 0 },
+        _ => Sample::Value(0.0),
+    });
+    *sample = match (*sample, measurement.kind) {
+        (Sample::Value(total), MetricKind::Counter) => Sample::Value(total + value),
+        (Sample::Value(_), _) => Sample::Value(value),
+        (Sample::Summary { sum, count }, _) => Sample::Summary { sum: sum + value, count: count + 1 },
+    };
+}
+
+// Prometheus names only allow letters, digits, underscores and colons, and by convention end
+// with the unit
+fn metric_name(name: &str, unit: Unit) -> String {
+    let mut metric_name: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' })
+        .collect();
+    if metric_name.starts_with(|c: char| c.is_ascii_digit()) {
+        metric_name.insert(0, '_');
+    }
+    let unit_suffix = match unit {
+        Unit::Bytes => "_bytes",
+        Unit::Percent => "_percent",
+        Unit::Milliseconds => "_seconds",
+        Unit::None => "",
+    };
+    if !metric_name.ends_with(unit_suffix) {
+        metric_name.push_str(unit_suffix);
+    }
+    metric_name
+}
+
+fn help_text(measurement: &Measurement) -> String {
+    let unit = match measurement.unit {
+        Unit::Bytes => " in bytes",
+        Unit::Percent => " as a percentage",
+        Unit::Milliseconds => " in seconds",
+        Unit::None => "",
+    };
+    format!("{}{}", measurement.name, unit).replace('\\', r"\\").replace('\n', r"\n")
+}
+
+fn render_labels(measurement: &Measurement) -> String {
+    if measurement.tags.is_empty() {
+        return String::new();
+    }
+    let labels: Vec<String> = measurement.tags
+        .iter()
+        .map(|(key, value)| format!("{}=\"{}\"", label_name(key), escape_label_value(value)))
+        .collect();
+    "{".to_string() + &labels.join(",") + "}"
+}
+
+fn label_name(key: &str) -> String {
+    let mut name: String = key.chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' }).collect();
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+    name
+}
+
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', r"\\").replace('"', "\\\"").replace('\n', r"\n")
+}
+
+fn render(families: &Families) -> String {
+    let mut body = String::new();
+    for (name, family) in families {
+        writeln!(body, "# HELP {} {}", name, family.help).unwrap();
+        writeln!(body, "# TYPE {} {}", name, family.family_type).unwrap();
+        for (labels, sample) in &family.samples {
+            match *sample {
+                Sample::Value(value) => writeln!(body, "{}{} {}", name, labels, value).unwrap(),
+                Sample::Summary { sum, count } => {
+                    writeln!(body, "{}_sum{} {}", name, labels, sum).unwrap();
+                    writeln!(body, "{}_count{} {}", name, labels, count).unwrap();
+                }
+            }
+        }
+    }
+    body
+}
+
+fn serve_connection(mut stream: TcpStream, families: &Mutex<Families>) -> Result<()> {
+    stream.set_read_timeout(Some(CONNECTION_TIMEOUT))?;
+    stream.set_write_timeout(Some(CONNECTION_TIMEOUT))?;
+    let request_line = read_request_line(&mut stream)?;
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path)) if path.split('?').next() == Some(METRICS_PATH) => {
+            self_metrics::count("prometheus.scrapes", 1);
+            ("200 OK", render(&families.lock().unwrap()))
+        },
+        (Some("GET"), Some(_)) => ("404 Not Found", "Metrics are served at /metrics\n".to_string()),
+        _ => ("405 Method Not Allowed", "Only GET is supported\n".to_string()),
+    };
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+           status, CONTENT_TYPE, body.len(), body)?;
+    stream.flush()
+}
+
+// Only the request line matters, but the headers are read too so that closing the connection
+// doesn't reset it while the scraper is still sending
+fn read_request_line(stream: &mut TcpStream) -> Result<String> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST_BYTES {
+            return Err(Error::new(ErrorKind::InvalidData, "HTTP request headers too long"));
+        }
+        let read = stream.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    Ok(request.lines().next().unwrap_or("").to_string())
+}
+
+#[test]
+fn render_exposes_gauges_with_labels_and_totals_counters() {
+    let mut families = Families::new();
+    let gauge = Measurement::new("drive.free_bytes", 512.0, MetricKind::Gauge, Unit::Bytes)
+        .with_tag("mount", "/var \"data\"");
+    record(&mut families, &gauge);
+    let counter = Measurement::new("lines_agent.sensor.errors", 2.0, MetricKind::Counter, Unit::None);
+    record(&mut families, &counter);
+    record(&mut families, &counter);
+    assert_eq!(render(&families), "# HELP drive_free_bytes drive.free_bytes in bytes\n\
+                                   # TYPE drive_free_bytes gauge\n\
+                                   drive_free_bytes{mount=\"/var \\\"data\\\"\"} 512\n\
+                                   # HELP lines_agent_sensor_errors_total lines_agent.sensor.errors\n\
+                                   # TYPE lines_agent_sensor_errors_total counter\n\
+                                   lines_agent_sensor_errors_total 4\n");
+}
+
+#[test]
+fn render_summarises_timers_in_seconds() {
+    let mut families = Families::new();
+    record(&mut families, &Measurement::new("tick_latency", 250.0, MetricKind::Timer, Unit::Milliseconds));
+    record(&mut families, &Measurement::new("tick_latency", 750.0, MetricKind::Timer, Unit::Milliseconds));
+    assert!(render(&families).ends_with("tick_latency_seconds_sum 1\ntick_latency_seconds_count 2\n"));
+}
+
+#[test]
+fn bind_serves_metrics_over_http() {
+    let mut output = PrometheusOutput::bind("127.0.0.1:0").unwrap();
+    output.send(&[Measurement::new("cpu_time.busy_time", 12.0, MetricKind::Gauge, Unit::Percent)]).unwrap();
+    let mut stream = TcpStream::connect(output.local_address()).unwrap();
+    stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.ends_with("# TYPE cpu_time_busy_time_percent gauge\ncpu_time_busy_time_percent 12\n"));
+}
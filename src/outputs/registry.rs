@@ -0,0 +1,409 @@
+extern crate serde_humantime;
+extern crate serde_yaml;
+
+use super::{FileFormat, FileOutput, GraphiteOutput, GraphiteProtocol, InfluxOutput, OtlpEncoding, OtlpOutput, Output, PrometheusOutput,
+            StatsdOutput, StatsdProtocol};
+use super::file::Rotation;
+use super::graphite;
+use super::spool::{Spool, SpoolingOutput};
+use super::statsd;
+use super::statsd_sinks::{self, ResolvingUdpMetricSink};
+use cadence::MetricSink;
+use filter::GlobFilter;
+use serde::de::DeserializeOwned;
+use self::serde_humantime::De;
+use self::serde_yaml::{Mapping, Value};
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// One entry of the `outputs` section of the agent configuration
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutputConfig {
+    #[serde(rename = "type")]
+    pub output_type: String,
+    // Tells outputs of the same type apart in logs and self-metrics, defaulting to the type
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    // Glob patterns for the metric names to send, everything when empty
+    #[serde(default)]
+    pub include: Vec<String>,
+    // Glob patterns for metric names not to send, even if they are included
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    // Keeps batches that fail to send on disk to send later, for outputs that can tell
+    #[serde(default)]
+    pub spool: Option<SpoolConfig>,
+    // Settings specific to the output type, checked when the output is built
+    #[serde(default = "default_options")]
+    pub options: Value,
+}
+
+impl OutputConfig {
+    pub fn name(&self) -> &str {
+        self.name.as_ref().unwrap_or(&self.output_type)
+    }
+
+    pub fn filter(&self) -> GlobFilter {
+        GlobFilter::new(&self.include, &self.exclude)
+    }
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_options() -> Value {
+    Value::Null
+}
+
+/// Limits on how much an output keeps on disk while its destination is unreachable
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SpoolConfig {
+    #[serde(default = "default_spool_max_bytes")]
+    pub max_bytes: u64,
+    // Measurements older than this aren't worth sending any more
+    #[serde(with = "serde_humantime", default = "default_spool_max_age")]
+    pub max_age: Duration,
+}
+
+fn default_spool_max_bytes() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_spool_max_age() -> Duration {
+    Duration::from_secs(24 * 60 * 60)
+}
+
+/// What outputs may need to know about the agent they are running in
+pub struct OutputContext {
+    pub hostname: String,
+    // Where relative paths in output options are relative to
+    pub output_directory: PathBuf,
+    // Each spooling output gets a directory of its own in here
+    pub spool_directory: PathBuf,
+}
+
+struct OutputType {
+    name: &'static str,
+    build: fn(Value, &OutputContext) -> Result<Box<dyn Output>>,
+    // Whether failed sends can be spooled and sent again. Statsd is left out on purpose: even over
+    // tcp:// or unix://, where send errors come back, part of a failed send may already have
+    // arrived, and sending it again would count its counters twice. Prometheus is scraped rather
+    // than sending anything, and the file output has nowhere safer to keep a batch than its file.
+    spoolable: fn(&Value) -> bool,
+}
+
+fn always(_: &Value) -> bool {
+    true
+}
+
+fn never(_: &Value) -> bool {
+    false
+}
+
+static OUTPUT_TYPES: &[OutputType] = &[
+    OutputType { name: "statsd", build: build_statsd_output, spoolable: never },
+    OutputType { name: "prometheus", build: build_prometheus_output, spoolable: never },
+    OutputType { name: "graphite", build: build_graphite_output, spoolable: always },
+    OutputType { name: "influxdb", build: build_influxdb_output, spoolable: influxdb_spoolable },
+    OutputType { name: "otlp", build: build_otlp_output, spoolable: always },
+    OutputType { name: "file", build: build_file_output, spoolable: never },
+];
+
+/// Builds the output described by a configuration entry, failing if the type isn't one we know
+/// about or its options don't make sense for that type
+pub fn build_output(config: &OutputConfig, context: &OutputContext) -> Result<Box<dyn Output>> {
+    match OUTPUT_TYPES.iter().find(|output_type| output_type.name == config.output_type) {
+        Some(output_type) => {
+            let output = (output_type.build)(config.options.clone(), context)?;
+            match config.spool {
+                Some(ref spool_config) if (output_type.spoolable)(&config.options) => {
+                    let spool = Spool::open(&context.spool_directory.join(config.name()), config.name(),
+                                            spool_config.max_bytes, spool_config.max_age)?;
+                    Ok(Box::new(SpoolingOutput::new(output, spool)))
+                },
+                Some(_) => Err(Error::new(ErrorKind::InvalidInput,
+                                          format!("Output type '{}' can't spool", config.output_type))),
+                None => Ok(output),
+            }
+        },
+        None => {
+            let known_types: Vec<&str> = OUTPUT_TYPES.iter().map(|output_type| output_type.name).collect();
+            Err(Error::new(ErrorKind::InvalidInput,
+                           format!("Unknown output type '{}', expected one of: {}",
+                                   config.output_type, known_types.join(", "))))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StatsdOptions {
+    // Where to send, as udp://host:port, tcp://host:port, unix:///path or unixgram:///path
+    #[serde(default)]
+    url: Option<String>,
+    // A UDP host and port, the older way of saying where to send
+    #[serde(default)]
+    host: Option<String>,
+    #[serde(default = "default_statsd_port")]
+    port: u16,
+    // How long to keep sending to the addresses a UDP host name resolved to before looking again
+    #[serde(with = "serde_humantime", default = "default_statsd_dns_ttl")]
+    dns_ttl: Duration,
+    // Whether to send plain statsd, with tags folded into names, or DogStatsD with real tags
+    #[serde(default)]
+    protocol: StatsdProtocol,
+    // The container the agent is running in, which DogStatsD uses to attribute metrics
+    #[serde(default)]
+    container_id: Option<String>,
+    // Metrics are packed into packets of up to this many bytes, 0 sends one metric per packet
+    #[serde(default = "default_statsd_max_payload_bytes")]
+    max_payload_bytes: usize,
+}
+
+fn default_statsd_port() -> u16 {
+    8125
+}
+
+fn default_statsd_max_payload_bytes() -> usize {
+    statsd::DEFAULT_MAX_PAYLOAD_BYTES
+}
+
+fn default_statsd_dns_ttl() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn build_statsd_output(options: Value, context: &OutputContext) -> Result<Box<dyn Output>> {
+    let options: StatsdOptions = parse_options("statsd", options)?;
+    let sink: Box<dyn MetricSink + Send> = match (options.url.as_ref(), options.host.as_ref()) {
+        (Some(url), None) => statsd_sinks::sink_for_url(url, options.dns_ttl)?,
+        (None, Some(host)) => {
+            // IPv6 literals need brackets to be told apart from the port
+            let address = if host.contains(':') { format!("[{}]:{}", host, options.port) } else { format!("{}:{}", host, options.port) };
+            Box::new(ResolvingUdpMetricSink::new(&address, options.dns_ttl)?)
+        },
+        _ => return Err(Error::new(ErrorKind::InvalidInput,
+                                   "Invalid options for output type 'statsd': expected either url or host")),
+    };
+    let output = match options.protocol {
+        StatsdProtocol::Statsd => StatsdOutput::from_boxed_sink(&context.hostname, sink),
+        // DogStatsD gets the host from its tag, so it doesn't need to be in every name as well
+        StatsdProtocol::Dogstatsd => {
+            let output = StatsdOutput::from_boxed_sink("", sink).with_protocol(StatsdProtocol::Dogstatsd);
+            match options.container_id {
+                Some(ref container_id) => output.with_container_id(container_id),
+                None => output,
+            }
+        }
+    };
+    Ok(Box::new(output.with_max_payload_bytes(options.max_payload_bytes)))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PrometheusOptions {
+    // Where to serve /metrics, e.g. 0.0.0.0:9100
+    listen_address: String,
+}
+
+fn build_prometheus_output(options: Value, _: &OutputContext) -> Result<Box<dyn Output>> {
+    let options: PrometheusOptions = parse_options("prometheus", options)?;
+    let output = PrometheusOutput::bind(&options.listen_address)?;
+    info!("Serving metrics for Prometheus at http://{}/metrics", output.local_address());
+    Ok(Box::new(output))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GraphiteOptions {
+    // Carbon's address, e.g. graphite.home:2003
+    address: String,
+    #[serde(default)]
+    protocol: GraphiteProtocol,
+    // Where each metric goes in the Graphite tree, by default the hostname then the metric name
+    #[serde(default = "default_graphite_path_template")]
+    path_template: String,
+}
+
+fn default_graphite_path_template() -> String {
+    graphite::DEFAULT_PATH_TEMPLATE.to_string()
+}
+
+fn build_graphite_output(options: Value, _: &OutputContext) -> Result<Box<dyn Output>> {
+    let options: GraphiteOptions = parse_options("graphite", options)?;
+    Ok(Box::new(GraphiteOutput::new(&options.address, &options.path_template, options.protocol)))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InfluxOptions {
+    // Either udp://host:port or a full http:// write URL
+    url: String,
+    // API token for InfluxDB 2.x
+    #[serde(default)]
+    token: Option<String>,
+}
+
+fn build_influxdb_output(options: Value, _: &OutputContext) -> Result<Box<dyn Output>> {
+    let options: InfluxOptions = parse_options("influxdb", options)?;
+    Ok(Box::new(InfluxOutput::new(&options.url, options.token.as_deref())?))
+}
+
+// UDP drops a datagram without any error coming back, so only HTTP is worth spooling for
+fn influxdb_spoolable(options: &Value) -> bool {
+    parse_options::<InfluxOptions>("influxdb", options.clone())
+        .map(|options| !options.url.starts_with("udp://"))
+        .unwrap_or(false)
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OtlpOptions {
+    // The collector's metrics endpoint, e.g. http://collector:4318/v1/metrics
+    endpoint: String,
+    #[serde(default)]
+    encoding: OtlpEncoding,
+}
+
+fn build_otlp_output(options: Value, context: &OutputContext) -> Result<Box<dyn Output>> {
+    let options: OtlpOptions = parse_options("otlp", options)?;
+    Ok(Box::new(OtlpOutput::new(&options.endpoint, options.encoding, &context.hostname)?))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileOptions {
+    // Relative to the output directory, by default metrics/measurements.jsonl or .csv
+    #[serde(default)]
+    path: Option<PathBuf>,
+    #[serde(default)]
+    format: FileFormat,
+    // Starts a new file now and then rather than letting one grow forever
+    #[serde(default)]
+    rotation: Option<RotationOptions>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RotationOptions {
+    #[serde(default)]
+    max_bytes: Option<u64>,
+    #[serde(default)]
+    max_age: Option<De<Duration>>,
+    // How many old files to keep
+    #[serde(default = "default_rotation_count")]
+    count: u32,
+    // What old files are renamed to, with {} for their number, gzipped when it ends in .gz
+    #[serde(default)]
+    pattern: Option<String>,
+}
+
+fn default_rotation_count() -> u32 {
+    10
+}
+
+fn build_file_output(options: Value, context: &OutputContext) -> Result<Box<dyn Output>> {
+    let options: FileOptions = parse_options("file", options)?;
+    let default_path = match options.format {
+        FileFormat::JsonLines => "metrics/measurements.jsonl",
+        FileFormat::Csv => "metrics/measurements.csv",
+    };
+    let path = context.output_directory.join(options.path.unwrap_or_else(|| PathBuf::from(default_path)));
+    let rotation = match options.rotation {
+        Some(rotation) => {
+            if rotation.max_bytes.is_none() && rotation.max_age.is_none() {
+                return Err(Error::new(ErrorKind::InvalidInput,
+                                      "Invalid options for output type 'file': rotation needs max_bytes or max_age"));
+            }
+            let pattern = match rotation.pattern {
+                Some(pattern) => context.output_directory.join(pattern),
+                None => PathBuf::from(path.to_string_lossy().into_owned() + ".{}.gz"),
+            };
+            Some(Rotation::new(rotation.max_bytes, rotation.max_age.map(De::into_inner),
+                               &pattern.to_string_lossy(), rotation.count)?)
+        },
+        None => None,
+    };
+    Ok(Box::new(FileOutput::new(&path, options.format, rotation)?))
+}
+
+fn parse_options<T>(output_type: &str, options: Value) -> Result<T>
+where
+    T: DeserializeOwned,
+{
+    // Leaving out the options entirely is the same as giving none
+    let options = if options.is_null() { Value::Mapping(Mapping::new()) } else { options };
+    serde_yaml::from_value(options).map_err(|e| {
+        Error::new(ErrorKind::InvalidInput, format!("Invalid options for output type '{}': {}", output_type, e))
+    })
+}
+
+#[cfg(test)]
+fn test_context() -> OutputContext {
+    OutputContext {
+        hostname: "web-1".to_string(),
+        output_directory: ::std::env::temp_dir(),
+        spool_directory: ::std::env::temp_dir().join("lines-spool"),
+    }
+}
+
+#[test]
+fn build_output_rejects_unknown_type() {
+    let config: OutputConfig = serde_yaml::from_str("type: carrier_pigeon").unwrap();
+    let error = build_output(&config, &test_context()).err().unwrap();
+    assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    assert_eq!(error.to_string(),
+               "Unknown output type 'carrier_pigeon', expected one of: statsd, prometheus, graphite, influxdb, otlp, file");
+}
+
+#[test]
+fn build_output_requires_options_for_type() {
+    let config: OutputConfig = serde_yaml::from_str("type: graphite\noptions:\n  port: 2003").unwrap();
+    assert!(build_output(&config, &test_context()).is_err());
+}
+
+#[test]
+fn output_config_names_after_type_by_default() {
+    let config: OutputConfig =
+        serde_yaml::from_str("type: statsd\ninclude: [\"drive.*\"]\noptions:\n  host: localhost").unwrap();
+    assert_eq!(config.name(), "statsd");
+    assert!(config.filter().matches("drive.free_bytes"));
+    assert!(!config.filter().matches("cpu_time.busy_time"));
+    assert!(build_output(&config, &test_context()).is_ok());
+}
+
+#[test]
+fn build_output_rejects_spool_for_fire_and_forget_types() {
+    let config: OutputConfig = serde_yaml::from_str("type: statsd
+spool: {}
+options:
+  host: localhost").unwrap();
+    assert_eq!(build_output(&config, &test_context()).err().unwrap().to_string(), "Output type 'statsd' can't spool");
+}
+
+#[test]
+fn build_output_only_spools_influxdb_over_http() {
+    let udp_config: OutputConfig =
+        serde_yaml::from_str("type: influxdb\nspool: {}\noptions:\n  url: udp://localhost:8089").unwrap();
+    assert_eq!(build_output(&udp_config, &test_context()).err().unwrap().to_string(),
+               "Output type 'influxdb' can't spool");
+    let http_config: OutputConfig = serde_yaml::from_str(
+        "type: influxdb\nname: influx-http\nspool: {}\noptions:\n  url: http://localhost:8086/write?db=lines").unwrap();
+    assert!(build_output(&http_config, &test_context()).is_ok());
+}
+
+#[test]
+fn build_statsd_output_takes_either_url_or_host() {
+    let url_config: OutputConfig = serde_yaml::from_str("type: statsd\noptions:\n  url: tcp://localhost:8125").unwrap();
+    assert!(build_output(&url_config, &test_context()).is_ok());
+    let both_config: OutputConfig =
+        serde_yaml::from_str("type: statsd\noptions:\n  url: udp://localhost:8125\n  host: localhost").unwrap();
+    assert!(build_output(&both_config, &test_context()).is_err());
+    let neither_config: OutputConfig = serde_yaml::from_str("type: statsd").unwrap();
+    assert!(build_output(&neither_config, &test_context()).is_err());
+}
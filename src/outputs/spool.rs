@@ -0,0 +1,257 @@
+extern crate serde_json;
+
+use super::Output;
+#[cfg(test)]
+use super::test_measurement;
+use measurement::Measurement;
+#[cfg(test)]
+use measurement::{MetricKind, Unit};
+use self_metrics;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+#[cfg(test)]
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime};
+
+static SPOOL_FILE_EXTENSION: &str = "jsonl";
+// Replaying is spread over several sends after a long outage, so one send can't take forever
+const MAX_REPLAYED_BATCHES_PER_SEND: usize = 10;
+
+/// Batches that couldn't be sent, kept on disk in the order they should be sent in with one
+/// JSON Lines file per batch. The oldest batches are dropped once the spool is too big or they
+/// are too old to be worth sending.
+pub struct Spool {
+    directory: PathBuf,
+    max_bytes: u64,
+    max_age: Duration,
+    next_sequence: u64,
+    name: String,
+}
+
+impl Spool {
+    /// Opens the spool in the directory, picking up any batches left from before a restart
+    pub fn open(directory: &Path, name: &str, max_bytes: u64, max_age: Duration) -> Result<Spool> {
+        fs::create_dir_all(directory).map_err(|e| {
+            Error::new(e.kind(), format!("Error creating spool directory {}: {}", directory.display(), e))
+        })?;
+        let mut spool = Spool { directory: directory.to_path_buf(), max_bytes, max_age, next_sequence: 0, name: name.to_string() };
+        spool.next_sequence = spool.files()?
+            .last()
+            .and_then(|path| sequence_of(path))
+            .map_or(0, |sequence| sequence + 1);
+        Ok(spool)
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.files()?.is_empty())
+    }
+
+    /// Adds a batch after all the others, then drops the oldest batches past the limits
+    pub fn push(&mut self, measurements: &[Measurement]) -> Result<()> {
+        let path = self.directory.join(format!("{:020}.{}", self.next_sequence, SPOOL_FILE_EXTENSION));
+        let partial_path = path.with_extension("partial");
+        {
+            let mut writer = BufWriter::new(File::create(&partial_path)?);
+            for measurement in measurements {
+                serde_json::to_writer(&mut writer, measurement).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+                writer.write_all(b"\n")?;
+            }
+            writer.flush()?;
+        }
+        // Written under another name first so a crash can't leave half a batch to be replayed
+        fs::rename(&partial_path, &path)?;
+        self.next_sequence += 1;
+        self.count("spool.spooled_measurements", measurements.len());
+        self.enforce_limits()
+    }
+
+    /// The oldest batch and the file it came from, to be removed once it has been sent
+    pub fn oldest(&self) -> Result<Option<(PathBuf, Vec<Measurement>)>> {
+        for path in self.files()? {
+            match read_batch(&path) {
+                Ok(measurements) => return Ok(Some((path, measurements))),
+                Err(e) => {
+                    warn!("Discarding unreadable spool file {}: {}", path.display(), e);
+                    fs::remove_file(&path)?;
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn remove(&self, path: &Path) -> Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn enforce_limits(&self) -> Result<()> {
+        let now = SystemTime::now();
+        let mut files: Vec<(PathBuf, u64, SystemTime)> = Vec::new();
+        for path in self.files()? {
+            let metadata = fs::metadata(&path)?;
+            files.push((path, metadata.len(), metadata.modified().unwrap_or(now)));
+        }
+        let mut total_bytes: u64 = files.iter().map(|&(_, bytes, _)| bytes).sum();
+        for (path, bytes, modified) in files {
+            let too_old = now.duration_since(modified).map(|age| age > self.max_age).unwrap_or(false);
+            if !too_old && total_bytes <= self.max_bytes {
+                break;
+            }
+            let dropped = read_batch(&path).map(|measurements| measurements.len()).unwrap_or(0);
+            warn!("Dropping {} spooled measurements for output {} from {} to stay within the spool limits",
+                  dropped, self.name, path.display());
+            fs::remove_file(&path)?;
+            total_bytes -= bytes;
+            self.count("spool.dropped_measurements", dropped);
+        }
+        Ok(())
+    }
+
+    fn files(&self) -> Result<Vec<PathBuf>> {
+        let mut files: Vec<PathBuf> = fs::read_dir(&self.directory)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|extension| extension == SPOOL_FILE_EXTENSION))
+            .collect();
+        // Sequence numbers are zero padded, so name order is the order batches were spooled in
+        files.sort();
+        Ok(files)
+    }
+
+    fn count(&self, name: &str, measurements: usize) {
+        self_metrics::count_with_tags(name, &[("output", &self.name)], measurements as u64);
+    }
+}
+
+fn sequence_of(path: &Path) -> Option<u64> {
+    path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse().ok())
+}
+
+fn read_batch(path: &Path) -> Result<Vec<Measurement>> {
+    let mut measurements = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let measurement = serde_json::from_str(&line?).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        measurements.push(measurement);
+    }
+    Ok(measurements)
+}
+
+/// Keeps batches that an output fails to send in a spool and sends them again, oldest first and
+/// with their original timestamps, once the output is working again
+pub struct SpoolingOutput {
+    output: Box<dyn Output>,
+    spool: Spool,
+}
+
+impl SpoolingOutput {
+    pub fn new(output: Box<dyn Output>, spool: Spool) -> SpoolingOutput {
+        SpoolingOutput { output, spool }
+    }
+
+    // Returns whether the spool was emptied, stopping at the first batch that fails again
+    fn replay(&mut self) -> Result<bool> {
+        // Batches can go past the age limit during an outage without anything being pushed
+        self.spool.enforce_limits()?;
+        for _ in 0..MAX_REPLAYED_BATCHES_PER_SEND {
+            let (path, measurements) = match self.spool.oldest()? {
+                Some(batch) => batch,
+                None => return Ok(true),
+            };
+            self.output.send(&measurements)?;
+            self.spool.remove(&path)?;
+            self.spool.count("spool.replayed_measurements", measurements.len());
+        }
+        self.spool.is_empty()
+    }
+}
+
+impl Output for SpoolingOutput {
+    fn send(&mut self, measurements: &[Measurement]) -> Result<()> {
+        // New measurements wait behind spooled ones so that everything arrives in order
+        let result = match self.replay() {
+            Ok(true) => self.output.send(measurements),
+            Ok(false) => Ok(()),
+            Err(e) => Err(e),
+        };
+        let spooled = result.is_err() || !self.spool.is_empty()?;
+        if spooled {
+            self.spool.push(measurements)?;
+        }
+        result.map_err(|e| Error::new(e.kind(), format!("{}, spooled {} measurements to send later", e, measurements.len())))
+    }
+}
+
+#[cfg(test)]
+struct FlakyOutput {
+    up: Arc<AtomicBool>,
+    sent: Arc<Mutex<Vec<Measurement>>>,
+}
+
+#[cfg(test)]
+impl Output for FlakyOutput {
+    fn send(&mut self, measurements: &[Measurement]) -> Result<()> {
+        if !self.up.load(Ordering::SeqCst) {
+            return Err(Error::new(ErrorKind::ConnectionRefused, "connection refused"));
+        }
+        self.sent.lock().unwrap().extend_from_slice(measurements);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn test_spool_directory(name: &str) -> PathBuf {
+    let directory = ::std::env::temp_dir().join(format!("lines-spool-{}-{}", name, ::std::process::id()));
+    let _ = fs::remove_dir_all(&directory);
+    directory
+}
+
+#[test]
+fn send_spools_while_down_and_replays_in_order() {
+    let directory = test_spool_directory("replay");
+    let up = Arc::new(AtomicBool::new(false));
+    let sent = Arc::new(Mutex::new(Vec::new()));
+    let spool = Spool::open(&directory, "test", 1024 * 1024, Duration::from_secs(3600)).unwrap();
+    let mut output = SpoolingOutput::new(Box::new(FlakyOutput { up: up.clone(), sent: sent.clone() }), spool);
+    let batches: Vec<Measurement> = (1..4)
+        .map(|value| test_measurement("cpu_time.busy_time", value as f64, MetricKind::Gauge, Unit::Percent))
+        .collect();
+    assert!(output.send(&batches[0..1]).is_err());
+    assert!(output.send(&batches[1..2]).is_err());
+    up.store(true, Ordering::SeqCst);
+    output.send(&batches[2..3]).unwrap();
+    assert_eq!(*sent.lock().unwrap(), batches);
+    assert!(output.spool.is_empty().unwrap());
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn push_drops_oldest_batches_past_size_limit() {
+    let directory = test_spool_directory("limit");
+    let mut spool = Spool::open(&directory, "test", 300, Duration::from_secs(3600)).unwrap();
+    for value in 1..5 {
+        spool.push(&[test_measurement("cpu_time.busy_time", value as f64, MetricKind::Gauge, Unit::Percent)]).unwrap();
+    }
+    let (_, oldest) = spool.oldest().unwrap().unwrap();
+    assert!(oldest[0].value > 1.0);
+    // Reopening carries on after the newest batch rather than overwriting it
+    assert_eq!(Spool::open(&directory, "test", 300, Duration::from_secs(3600)).unwrap().next_sequence, 4);
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn send_drops_batches_that_aged_past_limit_instead_of_replaying() {
+    let directory = test_spool_directory("age");
+    let sent = Arc::new(Mutex::new(Vec::new()));
+    let mut spool = Spool::open(&directory, "test", 1024 * 1024, Duration::from_secs(3600)).unwrap();
+    let old = test_measurement("cpu_time.busy_time", 1.0, MetricKind::Gauge, Unit::Percent);
+    let new = vec![test_measurement("cpu_time.busy_time", 2.0, MetricKind::Gauge, Unit::Percent)];
+    spool.push(&[old]).unwrap();
+    let (path, _) = spool.oldest().unwrap().unwrap();
+    File::options().write(true).open(&path).unwrap()
+        .set_modified(SystemTime::now() - Duration::from_secs(7200)).unwrap();
+    let mut output = SpoolingOutput::new(Box::new(FlakyOutput { up: Arc::new(AtomicBool::new(true)), sent: sent.clone() }), spool);
+    output.send(&new).unwrap();
+    assert_eq!(*sent.lock().unwrap(), new);
+    fs::remove_dir_all(&directory).unwrap();
+}
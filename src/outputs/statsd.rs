@@ -0,0 +1,255 @@
+use super::{flatten_name, Output};
+use cadence::MetricSink;
+#[cfg(test)]
+use cadence::UdpMetricSink;
+use measurement::{Measurement, MetricKind};
+#[cfg(test)]
+use measurement::Unit;
+use self_metrics;
+use std::io::{Error, Result};
+#[cfg(test)]
+use std::net::UdpSocket;
+#[cfg(test)]
+use std::time::Duration;
+
+/// Fits in one Ethernet frame with room for the IP and UDP headers and any tunnelling on the way,
+/// so datagrams this size don't get fragmented
+pub const DEFAULT_MAX_PAYLOAD_BYTES: usize = 1432;
+
+/// Which flavour of the statsd line format to send
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StatsdProtocol {
+    /// Plain statsd, which has no tags, so the ones that tell series apart are folded into the name
+    #[default]
+    Statsd,
+    /// DogStatsD, which carries tags, distributions and the originating container on each line
+    Dogstatsd,
+}
+
+pub struct StatsdOutput {
+    prefix: String,
+    protocol: StatsdProtocol,
+    container_id: Option<String>,
+    max_payload_bytes: usize,
+    sink: Box<dyn MetricSink + Send>,
+}
+
+impl StatsdOutput {
+    pub fn new<T>(prefix: &str, sink: T) -> StatsdOutput
+    where
+        T: MetricSink + Send + 'static,
+    {
+        StatsdOutput::from_boxed_sink(prefix, Box::new(sink))
+    }
+
+    /// For when which kind of sink to use is only known at runtime
+    pub fn from_boxed_sink(prefix: &str, sink: Box<dyn MetricSink + Send>) -> StatsdOutput {
+        StatsdOutput {
+            prefix: prefix.to_string(),
+            protocol: StatsdProtocol::Statsd,
+            container_id: None,
+            max_payload_bytes: DEFAULT_MAX_PAYLOAD_BYTES,
+            sink,
+        }
+    }
+
+    pub fn with_protocol(mut self, protocol: StatsdProtocol) -> StatsdOutput {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Marks every metric as coming from this container, which only DogStatsD has a field for
+    pub fn with_container_id(mut self, container_id: &str) -> StatsdOutput {
+        self.container_id = Some(container_id.to_string());
+        self
+    }
+
+    /// Packs as many metrics as fit into each packet sent, one per packet when this is zero
+    pub fn with_max_payload_bytes(mut self, max_payload_bytes: usize) -> StatsdOutput {
+        self.max_payload_bytes = max_payload_bytes;
+        self
+    }
+}
+
+impl Output for StatsdOutput {
+    // Every packet is attempted even if earlier ones fail, since one lost datagram says nothing
+    // about whether the next one will make it
+    fn send(&mut self, measurements: &[Measurement]) -> Result<()> {
+        let mut lines: Vec<String> = Vec::new();
+        for measurement in measurements {
+            if !measurement.value.is_finite() {
+                warn!("Not sending non-finite value {} for metric {}", measurement.value, measurement.name);
+                continue;
+            }
+            let measurement_lines = match self.protocol {
+                StatsdProtocol::Statsd => format_lines(&self.prefix, measurement),
+                StatsdProtocol::Dogstatsd =>
+                    vec![format_dogstatsd_line(&self.prefix, self.container_id.as_deref(), measurement)],
+            };
+            lines.extend(measurement_lines);
+        }
+        let mut failed: u64 = 0;
+        let mut first_error: Option<Error> = None;
+        for payload in pack_lines(&lines, self.max_payload_bytes) {
+            if let Err(e) = self.sink.emit(&payload) {
+                failed += payload.matches('\n').count() as u64 + 1;
+                first_error = first_error.or(Some(e));
+            }
+        }
+        match first_error {
+            None => Ok(()),
+            Some(e) => {
+                self_metrics::count("statsd.send_errors", failed);
+                Err(Error::new(e.kind(), format!("{} of {} statsd metrics failed to send, first error: {}",
+                                                 failed, lines.len(), e)))
+            }
+        }
+    }
+}
+
+/// Joins lines with newlines into as few payloads as possible without going over the maximum,
+/// which statsd servers split up again. A line too long to share a payload goes on its own.
+fn pack_lines(lines: &[String], max_payload_bytes: usize) -> Vec<String> {
+    let mut payloads: Vec<String> = Vec::new();
+    let mut payload = String::new();
+    for line in lines {
+        if !payload.is_empty() && payload.len() + 1 + line.len() > max_payload_bytes {
+            payloads.push(payload);
+            payload = String::new();
+        }
+        if !payload.is_empty() {
+            payload.push('\n');
+        }
+        payload.push_str(line);
+    }
+    if !payload.is_empty() {
+        payloads.push(payload);
+    }
+    payloads
+}
+
+fn statsd_type(kind: MetricKind) -> &'static str {
+    match kind {
+        MetricKind::Gauge => "g",
+        MetricKind::Counter => "c",
+        MetricKind::Timer => "ms",
+        MetricKind::Histogram => "h",
+        MetricKind::Set => "s",
+        // Plain statsd has no distributions, and a histogram is the closest thing it has
+        MetricKind::Distribution => "h",
+    }
+}
+
+fn dogstatsd_type(kind: MetricKind) -> &'static str {
+    match kind {
+        MetricKind::Distribution => "d",
+        other => statsd_type(other),
+    }
+}
+
+fn prefixed_name(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+        name.to_string()
+    } else {
+        prefix.to_string() + "." + name
+    }
+}
+
+// Sample rates only mean something for the kinds that aggregators count occurrences of
+fn sample_rate_field(measurement: &Measurement) -> String {
+    match measurement.kind {
+        MetricKind::Gauge | MetricKind::Set => String::new(),
+        _ if measurement.sample_rate < 1.0 => format!("|@{}", measurement.sample_rate),
+        _ => String::new(),
+    }
+}
+
+fn format_lines(prefix: &str, measurement: &Measurement) -> Vec<String> {
+    let name = prefixed_name(prefix, &flatten_name(measurement));
+    let metric_type = statsd_type(measurement.kind);
+    let sample_rate = sample_rate_field(measurement);
+    if measurement.kind == MetricKind::Gauge && measurement.value < 0.0 {
+        // A gauge value with a sign is read as a change to the current value rather than a new
+        // value, so a negative level has to be sent as a reset to zero followed by a decrement
+        vec![format!("{}:0|{}", name, metric_type), format!("{}:{}|{}", name, measurement.value, metric_type)]
+    } else {
+        vec![format!("{}:{}|{}{}", name, measurement.value, metric_type, sample_rate)]
+    }
+}
+
+// DogStatsD takes gauges at face value, so unlike plain statsd a negative gauge is a single line
+fn format_dogstatsd_line(prefix: &str, container_id: Option<&str>, measurement: &Measurement) -> String {
+    let mut line = format!("{}:{}|{}{}", prefixed_name(prefix, &measurement.name), measurement.value,
+                           dogstatsd_type(measurement.kind), sample_rate_field(measurement));
+    if !measurement.tags.is_empty() {
+        let tags: Vec<String> = measurement.tags
+            .iter()
+            .map(|(key, value)| dogstatsd_tag_part(key) + ":" + &dogstatsd_tag_part(value))
+            .collect();
+        line.push_str("|#");
+        line.push_str(&tags.join(","));
+    }
+    if let Some(container_id) = container_id {
+        line.push_str("|c:");
+        line.push_str(container_id);
+    }
+    line
+}
+
+// Commas separate tags and pipes separate fields, so neither can appear inside a tag
+fn dogstatsd_tag_part(part: &str) -> String {
+    part.chars().map(|c| if c == ',' || c == '|' || c == '#' || c.is_whitespace() { '_' } else { c }).collect()
+}
+
+#[test]
+fn format_lines_uses_type_for_kind() {
+    let gauge = Measurement::new("physical_memory.free_bytes", 1024.0, MetricKind::Gauge, Unit::Bytes);
+    assert_eq!(format_lines("host", &gauge), vec!["host.physical_memory.free_bytes:1024|g"]);
+    let counter = Measurement::new("requests", 2.5, MetricKind::Counter, Unit::None);
+    assert_eq!(format_lines("", &counter), vec!["requests:2.5|c"]);
+}
+
+#[test]
+fn format_lines_resets_negative_gauges() {
+    let gauge = Measurement::new("temperature", -3.0, MetricKind::Gauge, Unit::None);
+    assert_eq!(format_lines("host", &gauge), vec!["host.temperature:0|g", "host.temperature:-3|g"]);
+}
+
+#[test]
+fn format_lines_adds_sample_rate_to_sampled_counters() {
+    let counter = Measurement::new("requests", 3.0, MetricKind::Counter, Unit::None).with_sample_rate(0.25);
+    assert_eq!(format_lines("host", &counter), vec!["host.requests:3|c|@0.25"]);
+}
+
+#[test]
+fn pack_lines_fills_payloads_up_to_maximum() {
+    let lines: Vec<String> = vec!["a:1|c".to_string(), "b:2|c".to_string(), "c:3|c".to_string(), "long_name:4|c".to_string()];
+    assert_eq!(pack_lines(&lines, 11), vec!["a:1|c\nb:2|c", "c:3|c", "long_name:4|c"]);
+    assert_eq!(pack_lines(&lines, 0).len(), 4);
+    assert!(pack_lines(&[], 11).is_empty());
+}
+
+#[test]
+fn format_dogstatsd_line_carries_tags_and_container() {
+    let gauge = Measurement::new("drive.free_bytes", -1.0, MetricKind::Gauge, Unit::Bytes)
+        .with_tag("mount", "/var/my data")
+        .with_tag("host", "web-1");
+    assert_eq!(format_dogstatsd_line("", Some("abc123"), &gauge),
+               "drive.free_bytes:-1|g|#host:web-1,mount:/var/my_data|c:abc123");
+}
+
+#[test]
+fn send_delivers_dogstatsd_lines_over_udp() {
+    let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+    listener.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let sink = UdpMetricSink::from(listener.local_addr().unwrap(), UdpSocket::bind("127.0.0.1:0").unwrap()).unwrap();
+    let mut output = StatsdOutput::new("", sink).with_protocol(StatsdProtocol::Dogstatsd);
+    let distribution = Measurement::new("request_time", 12.5, MetricKind::Distribution, Unit::Milliseconds)
+        .with_tag("host", "web-1")
+        .with_sample_rate(0.5);
+    output.send(&[distribution]).unwrap();
+    let mut buffer = [0; 512];
+    let length = listener.recv(&mut buffer).unwrap();
+    assert_eq!(&buffer[..length], &b"request_time:12.5|d|@0.5|#host:web-1"[..]);
+}
@@ -0,0 +1,298 @@
+use cadence::MetricSink;
+use super::write_reconnecting;
+use self_metrics;
+use std::io::{Error, ErrorKind, Result, Write};
+#[cfg(test)]
+use std::io::{BufRead, BufReader};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+#[cfg(test)]
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::net::{UnixDatagram, UnixStream};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+#[cfg(test)]
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+static CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+static WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Makes a sink for a statsd address given as a URL: `udp://host:port`, `tcp://host:port`,
+/// `unix:///path/to/stream.sock` or `unixgram:///path/to/datagram.sock`. UDP destinations are
+/// looked up again once `dns_ttl` has passed, TCP ones whenever they reconnect.
+pub fn sink_for_url(url: &str, dns_ttl: Duration) -> Result<Box<dyn MetricSink + Send>> {
+    let invalid = |reason: &str| Error::new(ErrorKind::InvalidInput, format!("Invalid statsd URL '{}': {}", url, reason));
+    let (scheme, address) = match url.find("://") {
+        Some(index) => (&url[..index], &url[index + 3..]),
+        None => return Err(invalid("expected a scheme like udp://, tcp://, unix:// or unixgram://")),
+    };
+    if address.is_empty() {
+        return Err(invalid("there is no address"));
+    }
+    match scheme {
+        "udp" => Ok(Box::new(ResolvingUdpMetricSink::new(address, dns_ttl)?)),
+        "tcp" => {
+            let address = address.to_string();
+            Ok(Box::new(StreamMetricSink::new(url, move || connect_tcp(&address))))
+        },
+        #[cfg(unix)]
+        "unix" => {
+            let path = PathBuf::from(address);
+            Ok(Box::new(StreamMetricSink::new(url, move || connect_unix(&path))))
+        },
+        #[cfg(unix)]
+        "unixgram" => Ok(Box::new(UnixDatagramMetricSink::new(Path::new(address))?)),
+        _ => Err(invalid("unsupported scheme")),
+    }
+}
+
+type Resolve = Box<dyn Fn(&str) -> Result<Vec<SocketAddr>> + Send>;
+
+/// Sends to a UDP host by name, looking the name up again every `ttl` so that the agent follows
+/// the aggregator when its DNS records move, and taking turns between the addresses when the
+/// name has several. Like cadence's UdpMetricSink it is unbuffered and its sockets don't block,
+/// so sending can't stall and send errors come back to the caller.
+pub struct ResolvingUdpMetricSink {
+    address: String,
+    ttl: Duration,
+    resolve: Resolve,
+    state: Mutex<ResolvedState>,
+}
+
+struct ResolvedState {
+    addresses: Vec<SocketAddr>,
+    resolved_at: Instant,
+    next: usize,
+    // Bound on first use, since a host may have no IPv6 and most names have only one family
+    ipv4_socket: Option<UdpSocket>,
+    ipv6_socket: Option<UdpSocket>,
+}
+
+impl ResolvingUdpMetricSink {
+    /// Looks the address up straight away so that one that doesn't resolve at all is caught when
+    /// the agent starts
+    pub fn new(address: &str, ttl: Duration) -> Result<ResolvingUdpMetricSink> {
+        ResolvingUdpMetricSink::with_resolver(address, ttl, resolve_address)
+    }
+
+    fn with_resolver<F>(address: &str, ttl: Duration, resolve: F) -> Result<ResolvingUdpMetricSink>
+    where
+        F: Fn(&str) -> Result<Vec<SocketAddr>> + Send + 'static,
+    {
+        let addresses = resolve(address)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("Invalid statsd address {}: {}", address, e)))?;
+        let state = ResolvedState { addresses, resolved_at: Instant::now(), next: 0, ipv4_socket: None, ipv6_socket: None };
+        Ok(ResolvingUdpMetricSink { address: address.to_string(), ttl, resolve: Box::new(resolve), state: Mutex::new(state) })
+    }
+
+    fn refresh(&self, state: &mut ResolvedState) {
+        state.resolved_at = Instant::now();
+        // The last addresses we had are the best guess while DNS is unavailable
+        match (self.resolve)(&self.address) {
+            Ok(addresses) => {
+                // Resolvers often shuffle records between lookups, which isn't a change
+                let (mut sorted, mut previous) = (addresses.clone(), state.addresses.clone());
+                sorted.sort();
+                previous.sort();
+                if sorted != previous {
+                    info!("Statsd address {} now resolves to {}, was {}",
+                          self.address, format_addresses(&addresses), format_addresses(&state.addresses));
+                    self_metrics::count("statsd.address_changes", 1);
+                    state.addresses = addresses;
+                    state.next = 0;
+                }
+            },
+            Err(e) => {
+                warn!("Error looking up statsd address {}, still sending to {}: {}",
+                      self.address, format_addresses(&state.addresses), e);
+                self_metrics::count("statsd.resolve_errors", 1);
+            }
+        }
+    }
+}
+
+impl MetricSink for ResolvingUdpMetricSink {
+    fn emit(&self, metric: &str) -> Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if state.resolved_at.elapsed() >= self.ttl {
+            self.refresh(&mut state);
+        }
+        let destination = state.addresses[state.next % state.addresses.len()];
+        state.next = state.next.wrapping_add(1);
+        let (socket, local_address) = match destination {
+            SocketAddr::V4(_) => (&mut state.ipv4_socket, "0.0.0.0:0"),
+            SocketAddr::V6(_) => (&mut state.ipv6_socket, "[::]:0"),
+        };
+        if socket.is_none() {
+            let new_socket = UdpSocket::bind(local_address)?;
+            new_socket.set_nonblocking(true)?;
+            *socket = Some(new_socket);
+        }
+        socket.as_ref().unwrap().send_to(metric.as_bytes(), destination)
+    }
+}
+
+fn resolve_address(address: &str) -> Result<Vec<SocketAddr>> {
+    let addresses: Vec<SocketAddr> = address.to_socket_addrs()?.collect();
+    if addresses.is_empty() {
+        return Err(Error::new(ErrorKind::NotFound, format!("No addresses found for {}", address)));
+    }
+    Ok(addresses)
+}
+
+fn format_addresses(addresses: &[SocketAddr]) -> String {
+    addresses.iter().map(|address| address.to_string()).collect::<Vec<String>>().join(", ")
+}
+
+type Connect = Box<dyn Fn() -> Result<Box<dyn Write + Send>> + Send>;
+
+/// Sends newline-framed metrics over a stream socket, connecting on first use and again after
+/// the connection breaks
+pub struct StreamMetricSink {
+    url: String,
+    connect: Connect,
+    stream: Mutex<Option<Box<dyn Write + Send>>>,
+}
+
+impl StreamMetricSink {
+    pub fn new<F>(url: &str, connect: F) -> StreamMetricSink
+    where
+        F: Fn() -> Result<Box<dyn Write + Send>> + Send + 'static,
+    {
+        StreamMetricSink { url: url.to_string(), connect: Box::new(connect), stream: Mutex::new(None) }
+    }
+}
+
+impl MetricSink for StreamMetricSink {
+    fn emit(&self, metric: &str) -> Result<usize> {
+        let line = metric.to_string() + "\n";
+        let mut stream = self.stream.lock().unwrap();
+        write_reconnecting(&mut stream, || (self.connect)(), line.as_bytes(), &self.url, "statsd.reconnects")?;
+        Ok(line.len())
+    }
+}
+
+fn connect_tcp(address: &str) -> Result<Box<dyn Write + Send>> {
+    let mut last_error = Error::new(ErrorKind::NotFound, format!("No addresses found for {}", address));
+    for socket_address in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&socket_address, CONNECT_TIMEOUT) {
+            Ok(stream) => {
+                stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                stream.set_nodelay(true)?;
+                return Ok(Box::new(stream));
+            },
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+#[cfg(unix)]
+fn connect_unix(path: &Path) -> Result<Box<dyn Write + Send>> {
+    let stream = UnixStream::connect(path)
+        .map_err(|e| Error::new(e.kind(), format!("Error connecting to {}: {}", path.display(), e)))?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    Ok(Box::new(stream))
+}
+
+/// Sends each payload it is given, which may hold several newline separated metrics, as one
+/// datagram to a Unix socket. Unlike UDP it doesn't lose datagrams when the receiver is busy,
+/// the sender finds out instead.
+#[cfg(unix)]
+pub struct UnixDatagramMetricSink {
+    path: PathBuf,
+    socket: UnixDatagram,
+}
+
+#[cfg(unix)]
+impl UnixDatagramMetricSink {
+    pub fn new(path: &Path) -> Result<UnixDatagramMetricSink> {
+        let socket = UnixDatagram::unbound()?;
+        socket.set_nonblocking(true)?;
+        Ok(UnixDatagramMetricSink { path: path.to_path_buf(), socket })
+    }
+}
+
+#[cfg(unix)]
+impl MetricSink for UnixDatagramMetricSink {
+    fn emit(&self, metric: &str) -> Result<usize> {
+        self.socket.send_to(metric.as_bytes(), &self.path)
+    }
+}
+
+#[test]
+fn sink_for_url_rejects_unknown_schemes() {
+    assert!(sink_for_url("stats.home:8125", Duration::from_secs(60)).is_err());
+    assert!(sink_for_url("http://stats.home:8125", Duration::from_secs(60)).is_err());
+    assert!(sink_for_url("udp://127.0.0.1:8125", Duration::from_secs(60)).is_ok());
+}
+
+#[test]
+fn resolving_udp_sink_follows_address_changes_and_rotates() {
+    let receivers: Vec<UdpSocket> = (0..3).map(|_| UdpSocket::bind("127.0.0.1:0").unwrap()).collect();
+    let receiver_addresses: Vec<SocketAddr> = receivers.iter().map(|receiver| receiver.local_addr().unwrap()).collect();
+    let resolved = Arc::new(Mutex::new(vec![receiver_addresses[0]]));
+    let resolver_addresses = resolved.clone();
+    let sink = ResolvingUdpMetricSink::with_resolver("stats.home:8125", Duration::from_millis(0), move |_| {
+        let addresses = resolver_addresses.lock().unwrap().clone();
+        if addresses.is_empty() { Err(Error::new(ErrorKind::NotFound, "no such host")) } else { Ok(addresses) }
+    }).unwrap();
+    let receive = |receiver: &UdpSocket| {
+        let mut buffer = [0; 64];
+        let length = receiver.recv(&mut buffer).unwrap();
+        String::from_utf8(buffer[..length].to_vec()).unwrap()
+    };
+    sink.emit("requests:1|c").unwrap();
+    assert_eq!(receive(&receivers[0]), "requests:1|c");
+    *resolved.lock().unwrap() = vec![receiver_addresses[1], receiver_addresses[2]];
+    sink.emit("requests:2|c").unwrap();
+    sink.emit("requests:3|c").unwrap();
+    assert_eq!(receive(&receivers[1]), "requests:2|c");
+    assert_eq!(receive(&receivers[2]), "requests:3|c");
+    // Failing lookups leave the sink sending to the addresses it last had
+    resolved.lock().unwrap().clear();
+    sink.emit("requests:4|c").unwrap();
+    assert_eq!(receive(&receivers[1]), "requests:4|c");
+}
+
+#[test]
+fn tcp_sink_sends_newline_framed_metrics_and_reconnects() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let sink = sink_for_url(&format!("tcp://{}", listener.local_addr().unwrap()), Duration::from_secs(60)).unwrap();
+    sink.emit("requests:1|c").unwrap();
+    let mut first_connection = BufReader::new(listener.accept().unwrap().0);
+    let mut line = String::new();
+    first_connection.read_line(&mut line).unwrap();
+    assert_eq!(line, "requests:1|c\n");
+    drop(first_connection);
+    // The first write after the aggregator goes away can still succeed, so keep sending until
+    // the broken connection is noticed and replaced
+    listener.set_nonblocking(true).unwrap();
+    let second_connection = loop {
+        sink.emit("requests:2|c").unwrap();
+        if let Ok((stream, _)) = listener.accept() {
+            break stream;
+        }
+        ::std::thread::sleep(Duration::from_millis(10));
+    };
+    second_connection.set_nonblocking(false).unwrap();
+    line.clear();
+    BufReader::new(second_connection).read_line(&mut line).unwrap();
+    assert_eq!(line, "requests:2|c\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn unixgram_sink_sends_each_payload_as_one_unframed_datagram() {
+    let path = ::std::env::temp_dir().join(format!("lines-statsd-{}.sock", ::std::process::id()));
+    let _ = ::std::fs::remove_file(&path);
+    let receiver = UnixDatagram::bind(&path).unwrap();
+    let sink = sink_for_url(&format!("unixgram://{}", path.display()), Duration::from_secs(60)).unwrap();
+    sink.emit("drive.free_bytes:5|g\ndrive.used_bytes:7|g").unwrap();
+    let mut buffer = [0; 64];
+    let length = receiver.recv(&mut buffer).unwrap();
+    assert_eq!(&buffer[..length], &b"drive.free_bytes:5|g\ndrive.used_bytes:7|g"[..]);
+    ::std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,217 @@
+use Sensor;
+use log_limiter::LogLimiter;
+use measurement::Measurement;
+use self_metrics;
+use std::any::Any;
+use std::cmp;
+use std::io::Error;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// A sensor that keeps failing is skipped for exponentially more runs, up to this many
+const MAX_BACKOFF_RUNS: u32 = 16;
+static ERROR_LOG_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+enum SenseFailure {
+    Error(Error),
+    Panic(String),
+}
+
+type SenseResult = Result<Vec<Measurement>, SenseFailure>;
+
+/// Runs a sensor on its own thread so that a sensor that hangs or panics only costs its own
+/// measurements rather than stalling or taking down every other sensor
+pub struct SensorRunner {
+    name: String,
+    requests: Sender<()>,
+    results: Receiver<SenseResult>,
+    // Whether a run was started by the last call to `start` and should be waited for
+    started: bool,
+    // Whether the sensor thread is still working on a run, possibly one we gave up waiting for
+    in_flight: bool,
+    consecutive_failures: u32,
+    runs_to_skip: u32,
+    error_log: LogLimiter,
+}
+
+impl SensorRunner {
+    pub fn new(name: &str, mut sensor: Box<dyn Sensor>) -> SensorRunner {
+        let (request_sender, request_receiver) = mpsc::channel::<()>();
+        let (result_sender, result_receiver) = mpsc::channel();
+        thread::Builder::new()
+            .name("sensor-".to_string() + name)
+            .spawn(move || {
+                for _ in request_receiver {
+                    let result = match panic::catch_unwind(AssertUnwindSafe(|| sensor.sense())) {
+                        Ok(Ok(measurements)) => Ok(measurements),
+                        Ok(Err(e)) => Err(SenseFailure::Error(e)),
+                        Err(payload) => Err(SenseFailure::Panic(panic_message(payload))),
+                    };
+                    if result_sender.send(result).is_err() {
+                        break;
+                    }
+                }
+            })
+            .unwrap();
+        SensorRunner {
+            name: name.to_string(),
+            requests: request_sender,
+            results: result_receiver,
+            started: false,
+            in_flight: false,
+            consecutive_failures: 0,
+            runs_to_skip: 0,
+            error_log: LogLimiter::new(ERROR_LOG_INTERVAL),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Starts a run of the sensor in the background, unless it is backing off after failures or
+    /// is still busy with a previous run that timed out
+    pub fn start(&mut self) {
+        self.started = false;
+        if self.runs_to_skip > 0 {
+            self.runs_to_skip -= 1;
+            debug!("Skipping sensor {} while backing off after {} failures", self.name, self.consecutive_failures);
+            self.count("skipped");
+            return;
+        }
+        if self.in_flight {
+            match self.results.try_recv() {
+                Ok(_) => {
+                    debug!("Discarding late result from sensor {}", self.name);
+                    self.in_flight = false;
+                },
+                Err(TryRecvError::Empty) => {
+                    self.error_log.error(&format!("Skipping sensor {} because its last run has not finished", self.name));
+                    self.count("skipped");
+                    return;
+                },
+                Err(TryRecvError::Disconnected) => {
+                    self.error_log.error(&format!("Skipping sensor {} because its thread has exited", self.name));
+                    self.count("skipped");
+                    return;
+                }
+            }
+        }
+        if self.requests.send(()).is_ok() {
+            self.started = true;
+            self.in_flight = true;
+        }
+    }
+
+    /// Waits until the deadline for the run started by `start`, returning its measurements. A
+    /// sensor that errors, panics or misses the deadline contributes no measurements.
+    pub fn finish(&mut self, deadline: Instant) -> Vec<Measurement> {
+        if !self.started {
+            return Vec::new();
+        }
+        self.started = false;
+        let now = Instant::now();
+        let result = if deadline > now {
+            self.results.recv_timeout(deadline.duration_since(now))
+        } else {
+            self.results.try_recv().map_err(|e| match e {
+                TryRecvError::Empty => RecvTimeoutError::Timeout,
+                TryRecvError::Disconnected => RecvTimeoutError::Disconnected,
+            })
+        };
+        match result {
+            Ok(Ok(measurements)) => {
+                self.in_flight = false;
+                self.consecutive_failures = 0;
+                return measurements;
+            },
+            Ok(Err(SenseFailure::Error(e))) => {
+                self.in_flight = false;
+                self.error_log.error(&format!("Error running sensor {}: {}", self.name, e));
+                self.count("errors");
+            },
+            Ok(Err(SenseFailure::Panic(message))) => {
+                self.in_flight = false;
+                self.error_log.error(&format!("Sensor {} panicked: {}", self.name, message));
+                self.count("panics");
+            },
+            Err(RecvTimeoutError::Timeout) => {
+                self.error_log.error(&format!("Sensor {} did not finish before its deadline", self.name));
+                self.count("timeouts");
+            },
+            Err(RecvTimeoutError::Disconnected) => {
+                self.in_flight = false;
+                self.error_log.error(&format!("Thread for sensor {} has exited", self.name));
+                self.count("errors");
+            }
+        }
+        self.back_off();
+        Vec::new()
+    }
+
+    /// Runs the sensor and waits for its measurements, giving up after the timeout
+    pub fn run(&mut self, timeout: Duration) -> Vec<Measurement> {
+        let deadline = Instant::now() + timeout;
+        self.start();
+        self.finish(deadline)
+    }
+
+    fn back_off(&mut self) {
+        self.consecutive_failures += 1;
+        // The first failure is retried straight away, after that each failure doubles the wait
+        let doubling = cmp::min(self.consecutive_failures - 1, 31);
+        self.runs_to_skip = cmp::min((1u32 << doubling) - 1, MAX_BACKOFF_RUNS);
+    }
+
+    fn count(&self, outcome: &str) {
+        self_metrics::count_with_tags(&("sensor.".to_string() + outcome), &[("sensor", &self.name)], 1);
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic payload".to_string()
+    }
+}
+
+#[cfg(test)]
+struct TestSensor(fn() -> ::std::io::Result<Vec<Measurement>>);
+
+#[cfg(test)]
+impl Sensor for TestSensor {
+    fn sense(&mut self) -> ::std::io::Result<Vec<Measurement>> {
+        (self.0)()
+    }
+}
+
+#[test]
+fn run_survives_panicking_sensor_and_backs_off() {
+    let mut runner = SensorRunner::new("panicking", Box::new(TestSensor(|| panic!("sensor exploded"))));
+    assert!(runner.run(Duration::from_secs(5)).is_empty());
+    assert!(runner.run(Duration::from_secs(5)).is_empty());
+    assert_eq!(runner.consecutive_failures, 2);
+    assert_eq!(runner.runs_to_skip, 1);
+    runner.start();
+    assert!(!runner.started);
+}
+
+#[test]
+fn run_gives_up_on_hanging_sensor() {
+    let mut runner = SensorRunner::new("hanging", Box::new(TestSensor(|| {
+        thread::sleep(Duration::from_secs(2));
+        Ok(Vec::new())
+    })));
+    let started = Instant::now();
+    assert!(runner.run(Duration::from_millis(50)).is_empty());
+    assert!(started.elapsed() < Duration::from_secs(1));
+    // The first run is still going, so the next one doesn't start
+    runner.start();
+    assert!(!runner.started);
+    assert!(runner.in_flight);
+}
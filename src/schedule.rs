@@ -0,0 +1,121 @@
+use log_limiter::LogLimiter;
+use self_metrics;
+use std::thread;
+use std::time::{Duration, Instant};
+
+static OVERRUN_LOG_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// What to do about ticks that should have happened while the previous tick was still running
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverrunPolicy {
+    /// Drop the missed ticks and wait for the next tick that is still in the future
+    Skip,
+    /// Run a single tick straight away in place of all the missed ones
+    #[default]
+    Coalesce,
+}
+
+/// Keeps ticks on a fixed grid of intervals measured with the monotonic clock, so neither slow
+/// ticks nor changes to the wall clock make the schedule drift
+pub struct Schedule {
+    name: String,
+    interval: Duration,
+    policy: OverrunPolicy,
+    next_tick: Instant,
+    overrun_log: LogLimiter,
+}
+
+impl Schedule {
+    /// Creates a schedule whose first tick is due immediately
+    pub fn new(name: &str, interval: Duration, policy: OverrunPolicy) -> Schedule {
+        Schedule {
+            name: name.to_string(),
+            interval,
+            policy,
+            next_tick: Instant::now(),
+            overrun_log: LogLimiter::new(OVERRUN_LOG_INTERVAL),
+        }
+    }
+
+    /// Sleeps until the next tick is due, returning the number of ticks that were missed
+    pub fn wait(&mut self) -> u64 {
+        let (overran, missed_ticks) = self.advance(Instant::now());
+        let tags = [("sensor", self.name.as_str())];
+        if overran {
+            self.overrun_log.error(&format!("Collection for {} overran its {:.3}s interval, missed {} ticks ({:?} policy)",
+                                            self.name, duration_in_seconds(&self.interval), missed_ticks, self.policy));
+            self_metrics::count_with_tags("scheduler.overruns", &tags, 1);
+            self_metrics::count_with_tags("scheduler.missed_ticks", &tags, missed_ticks);
+        }
+        let now = Instant::now();
+        if self.next_tick > now {
+            let time_until_next_tick = self.next_tick.duration_since(now);
+            debug!("Time until next tick: {:.3}s", duration_in_seconds(&time_until_next_tick));
+            thread::sleep(time_until_next_tick);
+        }
+        let woke_at = Instant::now();
+        let latency = if woke_at > self.next_tick { woke_at.duration_since(self.next_tick) } else { Duration::from_millis(0) };
+        self_metrics::time_with_tags("scheduler.tick_latency", &tags, latency);
+        missed_ticks
+    }
+
+    // Moves the schedule on to the tick after the current one as of `now`, returning whether that
+    // tick had already passed and how many ticks were skipped over. A run that takes between one
+    // and two intervals overruns without skipping anything when missed ticks are coalesced.
+    fn advance(&mut self, now: Instant) -> (bool, u64) {
+        let scheduled_tick = self.next_tick + self.interval;
+        if now <= scheduled_tick || self.interval == Duration::from_millis(0) {
+            self.next_tick = scheduled_tick;
+            return (false, 0);
+        }
+        let missed_ticks = duration_in_nanos(&now.duration_since(scheduled_tick)) / duration_in_nanos(&self.interval);
+        let ticks_to_skip = match self.policy {
+            OverrunPolicy::Coalesce => missed_ticks,
+            OverrunPolicy::Skip => missed_ticks + 1,
+        };
+        self.next_tick = scheduled_tick + self.interval * ticks_to_skip as u32;
+        (true, ticks_to_skip)
+    }
+}
+
+fn duration_in_nanos(duration: &Duration) -> u64 {
+    duration.as_secs() * 1_000_000_000 + duration.subsec_nanos() as u64
+}
+
+fn duration_in_seconds(duration: &Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1_000_000_000_f64
+}
+
+#[test]
+fn advance_stays_on_grid_without_overrun() {
+    let mut schedule = Schedule::new("test", Duration::from_secs(10), OverrunPolicy::Skip);
+    let start = schedule.next_tick;
+    assert_eq!(schedule.advance(start + Duration::from_secs(3)), (false, 0));
+    assert_eq!(schedule.next_tick, start + Duration::from_secs(10));
+}
+
+#[test]
+fn advance_coalesces_missed_ticks_into_one() {
+    let mut schedule = Schedule::new("test", Duration::from_secs(10), OverrunPolicy::Coalesce);
+    let start = schedule.next_tick;
+    assert_eq!(schedule.advance(start + Duration::from_secs(35)), (true, 2));
+    // Due in the past, so the next tick runs straight away and then the grid carries on
+    assert_eq!(schedule.next_tick, start + Duration::from_secs(30));
+}
+
+#[test]
+fn advance_skips_to_next_future_tick() {
+    let mut schedule = Schedule::new("test", Duration::from_secs(10), OverrunPolicy::Skip);
+    let start = schedule.next_tick;
+    assert_eq!(schedule.advance(start + Duration::from_secs(35)), (true, 3));
+    assert_eq!(schedule.next_tick, start + Duration::from_secs(40));
+}
+
+#[test]
+fn advance_reports_overrun_shorter_than_two_intervals() {
+    let mut schedule = Schedule::new("test", Duration::from_secs(10), OverrunPolicy::Coalesce);
+    let start = schedule.next_tick;
+    assert_eq!(schedule.advance(start + Duration::from_secs(15)), (true, 0));
+    assert_eq!(schedule.next_tick, start + Duration::from_secs(10));
+}
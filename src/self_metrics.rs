@@ -0,0 +1,83 @@
+use Sensor;
+use measurement::{Measurement, MetricKind, Unit};
+use std::collections::BTreeMap;
+use std::io::Result;
+use std::sync::Mutex;
+use std::time::Duration;
+
+const METRICS_PREFIX: &str = "lines_agent";
+
+type Tags = BTreeMap<String, String>;
+
+lazy_static! {
+    // Counts accumulated since the last time they were taken, keyed by metric name and tags
+    static ref COUNTERS: Mutex<BTreeMap<(String, Tags), u64>> = Mutex::new(BTreeMap::new());
+    // Individual timings recorded since the last time they were taken, in milliseconds
+    static ref TIMINGS: Mutex<Vec<(String, Tags, f64)>> = Mutex::new(Vec::new());
+}
+
+/// Adds to a counter about the agent itself, to be reported alongside sensor measurements
+pub fn count(name: &str, value: u64) {
+    count_with_tags(name, &[], value)
+}
+
+/// Adds to a counter about one part of the agent, like a particular sensor
+pub fn count_with_tags(name: &str, tags: &[(&str, &str)], value: u64) {
+    let mut counters = COUNTERS.lock().unwrap();
+    *counters.entry((METRICS_PREFIX.to_string() + "." + name, to_tags(tags))).or_insert(0) += value;
+}
+
+/// Records how long something one part of the agent did took
+pub fn time_with_tags(name: &str, tags: &[(&str, &str)], duration: Duration) {
+    let milliseconds = duration.as_secs() as f64 * 1000.0 + duration.subsec_nanos() as f64 / 1_000_000.0;
+    TIMINGS.lock().unwrap().push((METRICS_PREFIX.to_string() + "." + name, to_tags(tags), milliseconds));
+}
+
+fn to_tags(tags: &[(&str, &str)]) -> Tags {
+    tags.iter().map(|&(key, value)| (key.to_string(), value.to_string())).collect()
+}
+
+/// Takes everything counted and timed since the last call. Counters that have been seen before
+/// are reported as zero rather than omitted so that they show up as flat lines instead of gaps
+pub fn take_measurements() -> Vec<Measurement> {
+    let mut counters = COUNTERS.lock().unwrap();
+    let mut measurements: Vec<Measurement> = counters
+        .iter_mut()
+        .map(|((name, tags), value)| {
+            let measurement = with_name_tags(Measurement::new(name, *value as f64, MetricKind::Counter, Unit::None), tags);
+            *value = 0;
+            measurement
+        })
+        .collect();
+    let mut timings = TIMINGS.lock().unwrap();
+    measurements.extend(timings.drain(..).map(|(name, tags, milliseconds)| {
+        with_name_tags(Measurement::new(&name, milliseconds, MetricKind::Timer, Unit::Milliseconds), &tags)
+    }));
+    measurements
+}
+
+// The sensor or output a metric about the agent is for is part of what it measures
+fn with_name_tags(measurement: Measurement, tags: &Tags) -> Measurement {
+    tags.iter().fold(measurement, |measurement, (key, value)| measurement.with_name_tag(key, value))
+}
+
+/// Reports the agent's own metrics as though they were measured by a sensor, so that they get
+/// scheduled and sent like everything else
+pub struct SelfMetricsSensor;
+
+impl Sensor for SelfMetricsSensor {
+    fn sense(&mut self) -> Result<Vec<Measurement>> {
+        Ok(take_measurements())
+    }
+}
+
+#[test]
+fn take_measurements_resets_counters_to_zero() {
+    count_with_tags("test.take_measurements_resets", &[("sensor", "test")], 2);
+    count_with_tags("test.take_measurements_resets", &[("sensor", "test")], 3);
+    let value_of = |measurements: Vec<Measurement>| {
+        measurements.into_iter().find(|m| m.name == "lines_agent.test.take_measurements_resets").unwrap().value
+    };
+    assert_eq!(value_of(take_measurements()), 5.0);
+    assert_eq!(value_of(take_measurements()), 0.0);
+}
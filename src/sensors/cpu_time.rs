@@ -1,12 +1,12 @@
-extern crate cadence;
-
 use super::Sensor;
 
 pub type CpuTimeSensor = platform::PlatformCpuTimeSensor;
 
 impl CpuTimeSensor {
-    pub fn new() -> CpuTimeSensor {
-        platform::PlatformCpuTimeSensor::init()
+    /// Reports busy and idle time across all CPUs, and optionally for each core and broken down
+    /// by what the time was spent on. Only Linux has the breakdowns.
+    pub fn new(per_core: bool, per_mode: bool) -> CpuTimeSensor {
+        platform::PlatformCpuTimeSensor::init(per_core, per_mode)
     }
 }
 
@@ -17,8 +17,8 @@ mod platform {
 
     use super::Sensor;
     use super::CpuTimeSensor;
-    use cadence::prelude::*;
-    use cadence::StatsdClient;
+    use measurement::{Measurement, MetricKind, Unit};
+    use std::io::Result;
     use std::mem;
     use self::winapi::um::pdh;
     use self::winapi::um::pdh::{PDH_HQUERY, PDH_HCOUNTER, PDH_FMT_DOUBLE, PDH_FMT_COUNTERVALUE};
@@ -27,9 +27,10 @@ mod platform {
     use std::ffi::OsString;
 
     const FALSE: i32 = 0;
-    const ALL_CPU_TIME_PERFORMANCE_QUERY_STRING: &'static str = r"\Processor(_Total)\% Processor Time";
-    const FATAL_ERROR: &'static str = "Fatal error counting metric";
-    const METRICS_PREFIX: &'static str = "cpu_time";
+    const ALL_CPU_TIME_PERFORMANCE_QUERY_STRING: &str = r"\Processor(_Total)\% Processor Time";
+    const METRICS_PREFIX: &str = "cpu_time";
+    const CPU_TAG: &str = "cpu";
+    const ALL_CPUS: &str = "total";
     lazy_static! {
         static ref IDLE_TIME: String = METRICS_PREFIX.to_string() + ".idle_time";
         static ref BUSY_TIME: String = METRICS_PREFIX.to_string() + ".busy_time";
@@ -49,7 +50,7 @@ mod platform {
     unsafe impl Send for PlatformCpuTimeSensor {}
 
     impl PlatformCpuTimeSensor {
-        pub fn init() -> CpuTimeSensor {
+        pub fn init(_per_core: bool, _per_mode: bool) -> CpuTimeSensor {
             let all_cpu_time_query: Vec<u16> =
                 OsString::from(ALL_CPU_TIME_PERFORMANCE_QUERY_STRING.to_string()).encode_wide().collect();
             let mut query: PDH_HQUERY = unsafe { mem::zeroed() };
@@ -75,14 +76,16 @@ mod platform {
     }
 
     impl Sensor for PlatformCpuTimeSensor {
-        fn sense(&mut self, statsd_client: &StatsdClient) {
+        fn sense(&mut self) -> Result<Vec<Measurement>> {
             let busy_percentage_during_interval = single_double_sample(self.query, self.cpu_percent_counter);
             info!("CPU busy percentage: {:.3}", busy_percentage_during_interval);
-            let rounded_busy_percentage: i64 = busy_percentage_during_interval.round() as i64;
-            statsd_client.count(&BUSY_TIME, rounded_busy_percentage)
-                .expect(FATAL_ERROR);
-            statsd_client.count(&IDLE_TIME, 100 - rounded_busy_percentage)
-                .expect(FATAL_ERROR);
+            let rounded_busy_percentage = busy_percentage_during_interval.round();
+            Ok(vec![
+                Measurement::new(&BUSY_TIME, rounded_busy_percentage, MetricKind::Gauge, Unit::Percent)
+                    .with_tag(CPU_TAG, ALL_CPUS),
+                Measurement::new(&IDLE_TIME, 100.0 - rounded_busy_percentage, MetricKind::Gauge, Unit::Percent)
+                    .with_tag(CPU_TAG, ALL_CPUS),
+            ])
         }
     }
 
@@ -111,100 +114,164 @@ mod platform {
 
 #[cfg(target_os="linux")]
 mod platform {
-    extern crate libc;
-    extern crate regex;
-    
     use super::Sensor;
-    use cadence::prelude::*;
-    use cadence::StatsdClient;
-    use std::io::{Error, ErrorKind, Result};
+    use sensors::wait_for_reading_interval;
+    use measurement::{Measurement, MetricKind, Unit};
+    use std::collections::BTreeMap;
     use std::fs::File;
-    use std::io::BufReader;
+    use std::io::{Error, ErrorKind, Result};
     use std::io::prelude::*;
-    use self::regex::{Regex, Captures};
+    use std::time::Instant;
 
-    const FATAL_ERROR: &'static str = "Fatal error counting metric";
-    const METRICS_PREFIX: &'static str = "cpu_time";
+    const METRICS_PREFIX: &str = "cpu_time";
+    const CPU_TAG: &str = "cpu";
+    const ALL_CPUS: &str = "total";
+    const MODE_TAG: &str = "mode";
+    // The columns of a cpu line in /proc/stat, in order. Older kernels leave off the last few.
+    const MODES: [&str; 10] =
+        ["user", "nice", "system", "idle", "iowait", "irq", "softirq", "steal", "guest", "guest_nice"];
+    const IDLE: usize = 3;
+    const IOWAIT: usize = 4;
+    // Guest time is also counted in user time, and guest_nice in nice, so they aren't added to
+    // the total again
+    const GUEST: usize = 8;
     lazy_static! {
         static ref IDLE_TIME: String = METRICS_PREFIX.to_string() + ".idle_time";
         static ref BUSY_TIME: String = METRICS_PREFIX.to_string() + ".busy_time";
-        static ref CPU_TIME: Regex =
-           Regex::new(&(r"^cpu\s+(?P<user_ticks>\d+)\s+(?P<nice_ticks>\d+)\s+(?P<system_ticks>\d+)\s+".to_string() +
-                          r"(?P<idle_ticks>\d+)\s+(?P<iowait_ticks>\d+)\s+(?P<irq_ticks>\d+)\s+" +
-                          r"(?P<softirq_ticks>\d+)\s+(?P<steal_ticks>\d+)")).unwrap(); 
+        // Tagged with the mode rather than named after it, since idle_time already means idle or
+        // waiting for IO
+        static ref MODE_TIME: String = METRICS_PREFIX.to_string() + ".mode_time";
     }
 
+    type Ticks = [u64; 10];
+
     pub struct PlatformCpuTimeSensor {
-        last_idle_ticks: u64,
-        last_busy_ticks: u64
+        per_core: bool,
+        per_mode: bool,
+        // The ticks each CPU had last time, keyed by its tag value
+        last_ticks: BTreeMap<String, Ticks>,
+        last_read: Instant,
     }
 
     impl PlatformCpuTimeSensor {
-        pub fn init() -> PlatformCpuTimeSensor {
-            let (starting_idle_ticks, starting_busy_ticks) =
-                cpu_time_from_stat().expect("Error getting initial cpu times from /proc/stat");
-            PlatformCpuTimeSensor { last_idle_ticks: starting_idle_ticks, last_busy_ticks: starting_busy_ticks }
+        pub fn init(per_core: bool, per_mode: bool) -> PlatformCpuTimeSensor {
+            let last_ticks = ticks_from_stat().expect("Error getting initial cpu times from /proc/stat");
+            PlatformCpuTimeSensor { per_core, per_mode, last_ticks, last_read: Instant::now() }
         }
     }
 
     impl Sensor for PlatformCpuTimeSensor {
-        fn sense(&mut self, statsd_client: &StatsdClient) {
-            match cpu_time_from_stat() {
-                Err(e) => {
-                    error!("Error getting cpu times from /proc/stat: {:?}", e);
-                    return
-                },
-                Ok((total_idle_ticks, total_busy_ticks)) => {
-                    let elapsed_idle_ticks = total_idle_ticks - self.last_idle_ticks;     
-                    let elapsed_busy_ticks = total_busy_ticks - self.last_busy_ticks;
-                    let total_elapsed_ticks = elapsed_idle_ticks + elapsed_busy_ticks;
-                    let busy_percentage_during_interval: f64 = (elapsed_busy_ticks as f64 / total_elapsed_ticks as f64) * 100 as f64;
-                    info!("CPU busy percentage: {:.3}", busy_percentage_during_interval);
-                    let rounded_busy_percentage: i64 = busy_percentage_during_interval.round() as i64;
-                    statsd_client.count(&BUSY_TIME, rounded_busy_percentage)
-                        .expect(FATAL_ERROR);
-                    statsd_client.count(&IDLE_TIME, 100 - rounded_busy_percentage)
-                        .expect(FATAL_ERROR);
+        fn sense(&mut self) -> Result<Vec<Measurement>> {
+            wait_for_reading_interval(self.last_read);
+            let ticks = ticks_from_stat()?;
+            self.last_read = Instant::now();
+            let mut measurements = Vec::new();
+            for (cpu, current) in &ticks {
+                if cpu != ALL_CPUS && !self.per_core {
+                    continue;
+                }
+                // A CPU that has just come online has nothing to compare against until next time
+                if let Some(previous) = self.last_ticks.get(cpu) {
+                    measurements.extend(measurements_between(cpu, previous, current, self.per_mode));
                 }
             }
+            self.last_ticks = ticks;
+            Ok(measurements)
+        }
+    }
+
+    // Percentages of the ticks that elapsed between two readings of one CPU's line
+    fn measurements_between(cpu: &str, previous: &Ticks, current: &Ticks, per_mode: bool) -> Vec<Measurement> {
+        let mut elapsed: Ticks = [0; 10];
+        for (index, elapsed_ticks) in elapsed.iter_mut().enumerate() {
+            // Counters can go backwards when a CPU goes offline and comes back
+            *elapsed_ticks = current[index].saturating_sub(previous[index]);
+        }
+        let total_elapsed_ticks: u64 = elapsed[..GUEST].iter().sum();
+        // No time has passed as far as the kernel can tell, so there's nothing to divide up
+        if total_elapsed_ticks == 0 {
+            return Vec::new();
+        }
+        let percentage = |ticks: u64| ticks as f64 / total_elapsed_ticks as f64 * 100.0;
+        let busy_percentage_during_interval = percentage(total_elapsed_ticks - elapsed[IDLE] - elapsed[IOWAIT]);
+        if cpu == ALL_CPUS {
+            info!("CPU busy percentage: {:.3}", busy_percentage_during_interval);
+        }
+        let rounded_busy_percentage = busy_percentage_during_interval.round();
+        // The totals keep the names they had before there were cores to tell apart
+        let tag_cpu = |measurement: Measurement| {
+            if cpu == ALL_CPUS { measurement.with_tag(CPU_TAG, cpu) } else { measurement.with_name_tag(CPU_TAG, cpu) }
+        };
+        let mut measurements = vec![
+            tag_cpu(Measurement::new(&BUSY_TIME, rounded_busy_percentage, MetricKind::Gauge, Unit::Percent)),
+            tag_cpu(Measurement::new(&IDLE_TIME, 100.0 - rounded_busy_percentage, MetricKind::Gauge, Unit::Percent)),
+        ];
+        if per_mode {
+            for (mode, &ticks) in MODES.iter().zip(elapsed.iter()) {
+                measurements.push(tag_cpu(Measurement::new(&MODE_TIME, percentage(ticks), MetricKind::Gauge, Unit::Percent))
+                    .with_name_tag(MODE_TAG, mode));
+            }
         }
+        measurements
     }
 
-    fn cpu_time_from_stat() -> Result<(u64, u64)> {
-        let cpu_info = File::open("/proc/stat")?;
-        for line in BufReader::new(cpu_info).lines() {
-            match line {
-                Ok(line) => {
-                    if let Some(captures) = CPU_TIME.captures(&line) {
-                        let total_idle_ticks =
-                            parse_capture("idle_ticks", &captures)? +
-                            parse_capture("iowait_ticks", &captures)?;
-                        let total_busy_ticks =
-                            parse_capture("user_ticks", &captures)? +
-                            parse_capture("nice_ticks", &captures)? +
-                            parse_capture("system_ticks", &captures)? +
-                            parse_capture("irq_ticks", &captures)? +
-                            parse_capture("softirq_ticks", &captures)? +
-                            parse_capture("steal_ticks", &captures)?;
-                        return Ok((total_idle_ticks, total_busy_ticks))
-                    }
-                },
-                Err(_) => break
-            } 
+    fn ticks_from_stat() -> Result<BTreeMap<String, Ticks>> {
+        let mut stat = String::new();
+        File::open("/proc/stat")?.read_to_string(&mut stat)?;
+        let ticks = parse_stat(&stat)?;
+        if !ticks.contains_key(ALL_CPUS) {
+            return Err(Error::new(ErrorKind::NotFound, "Could not find the cpu line in /proc/stat"));
         }
-        
-        let error_message = format!("Could not find match for cpu time regex {}",
-                                    CPU_TIME.as_str());
-        return Err(Error::new(ErrorKind::NotFound, error_message));
-    }
-
-    fn parse_capture(capture_key: &str, captures: &Captures) -> Result<u64> {
-        match captures[capture_key].parse() {
-            Ok(num) => return Ok(num),
-            Err(e) => {
-                let error_message = format!("Unable to parse regex match as number, got error {:?}", e);
-                return Err(Error::new(ErrorKind::NotFound, error_message))
+        Ok(ticks)
+    }
+
+    // The aggregate `cpu` line is keyed as the total, and `cpuN` lines by their number N
+    fn parse_stat(stat: &str) -> Result<BTreeMap<String, Ticks>> {
+        let mut ticks = BTreeMap::new();
+        for line in stat.lines() {
+            let mut fields = line.split_whitespace();
+            let cpu = match fields.next() {
+                Some("cpu") => ALL_CPUS.to_string(),
+                Some(label) if label.starts_with("cpu") => label["cpu".len()..].to_string(),
+                _ => continue,
+            };
+            let mut cpu_ticks: Ticks = [0; 10];
+            for (index, field) in fields.take(MODES.len()).enumerate() {
+                cpu_ticks[index] = field.parse().map_err(|e| {
+                    Error::new(ErrorKind::InvalidData, format!("Unable to parse {} ticks in /proc/stat line '{}': {}",
+                                                               MODES[index], line, e))
+                })?;
             }
-        } 
+            ticks.insert(cpu, cpu_ticks);
+        }
+        Ok(ticks)
+    }
+
+    #[test]
+    fn parse_stat_reads_total_and_cores() {
+        let stat = "cpu  100 0 50 800 10 0 5 0 0 0\ncpu0 60 0 20 400 5 0 3 0 0 0\ncpu1 40 0 30 400 5 0 2\n\
+                    intr 12345 0 0\nctxt 6789\n";
+        let ticks = parse_stat(stat).unwrap();
+        assert_eq!(ticks.keys().collect::<Vec<_>>(), vec!["0", "1", "total"]);
+        assert_eq!(ticks["1"], [40, 0, 30, 400, 5, 0, 2, 0, 0, 0]);
+    }
+
+    #[test]
+    fn measurements_between_divides_elapsed_ticks_by_mode() {
+        let previous: Ticks = [100, 0, 50, 800, 10, 0, 0, 0, 0, 0];
+        let current: Ticks = [140, 0, 60, 830, 20, 0, 0, 10, 20, 0];
+        let measurements = measurements_between("0", &previous, &current, true);
+        let value = |name: &str, mode: Option<&str>| measurements
+            .iter()
+            .find(|measurement| measurement.name == name && measurement.tags.get("mode").map(|m| m.as_str()) == mode)
+            .unwrap()
+            .value;
+        assert_eq!(value("cpu_time.busy_time", None), 60.0);
+        assert_eq!(value("cpu_time.idle_time", None), 40.0);
+        assert_eq!(value("cpu_time.mode_time", Some("idle")), 30.0);
+        assert_eq!(value("cpu_time.mode_time", Some("steal")), 10.0);
+        assert_eq!(value("cpu_time.mode_time", Some("guest")), 20.0);
+        assert_eq!(measurements[0].tags["cpu"], "0");
+        assert!(measurements_between("0", &current, &current, true).is_empty());
     }
 }
@@ -0,0 +1,263 @@
+use super::Sensor;
+use filter::GlobFilter;
+use std::io::Result;
+
+pub struct DiskIoSensor {
+    // Which block devices to report on, by name
+    filter: GlobFilter,
+    // Partitions are left out unless asked for, since their disk already counts their I/O
+    partitions: bool,
+    last_reading: Option<platform::Reading>,
+}
+
+impl DiskIoSensor {
+    /// Fails on platforms we can't read disk counters on, so that configuring one stops the agent
+    /// up front rather than failing on every run
+    pub fn new(filter: GlobFilter, partitions: bool) -> Result<DiskIoSensor> {
+        let last_reading = platform::first_reading()?;
+        Ok(DiskIoSensor { filter, partitions, last_reading })
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use super::Sensor;
+    use super::DiskIoSensor;
+    use measurement::Measurement;
+    use std::io::{Error, ErrorKind, Result};
+
+    pub enum Reading {}
+
+    pub fn first_reading() -> Result<Option<Reading>> {
+        Err(Error::new(ErrorKind::InvalidInput, "The disk_io sensor is only available on Linux"))
+    }
+
+    impl Sensor for DiskIoSensor {
+        fn sense(&mut self) -> Result<Vec<Measurement>> {
+            first_reading().map(|_| Vec::new())
+        }
+    }
+}
+
+#[cfg(target_os="linux")]
+mod platform {
+    use super::Sensor;
+    use super::DiskIoSensor;
+    use measurement::{Measurement, MetricKind, Unit};
+    use sensors::wait_for_reading_interval;
+    use std::collections::BTreeMap;
+    use std::fs::File;
+    use std::io::{Error, ErrorKind, Result};
+    use std::io::prelude::*;
+    use std::path::Path;
+    use std::time::Instant;
+    #[cfg(test)]
+    use filter::GlobFilter;
+    #[cfg(test)]
+    use sensors::MIN_READING_INTERVAL;
+
+    const METRICS_PREFIX: &str = "disk_io";
+    const DEVICE_TAG: &str = "device";
+    // /proc/diskstats counts in 512 byte sectors whatever the device's real sector size is
+    const SECTOR_BYTES: f64 = 512.0;
+    lazy_static! {
+        static ref READS: String = METRICS_PREFIX.to_string() + ".reads_per_second";
+        static ref WRITES: String = METRICS_PREFIX.to_string() + ".writes_per_second";
+        static ref READ_BYTES: String = METRICS_PREFIX.to_string() + ".read_bytes_per_second";
+        static ref WRITTEN_BYTES: String = METRICS_PREFIX.to_string() + ".written_bytes_per_second";
+        static ref READ_AWAIT: String = METRICS_PREFIX.to_string() + ".read_await";
+        static ref WRITE_AWAIT: String = METRICS_PREFIX.to_string() + ".write_await";
+        static ref QUEUE_DEPTH: String = METRICS_PREFIX.to_string() + ".queue_depth";
+        static ref UTILIZATION: String = METRICS_PREFIX.to_string() + ".utilization";
+    }
+
+    /// The counters of one line of /proc/diskstats that we use
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct DeviceCounters {
+        reads: u64,
+        sectors_read: u64,
+        read_milliseconds: u64,
+        writes: u64,
+        sectors_written: u64,
+        write_milliseconds: u64,
+        // Time the device had any I/O in flight, and that time weighted by how much was in flight
+        io_milliseconds: u64,
+        weighted_io_milliseconds: u64,
+    }
+
+    pub struct Reading {
+        taken_at: Instant,
+        devices: BTreeMap<String, DeviceCounters>,
+    }
+
+    pub fn first_reading() -> Result<Option<Reading>> {
+        read_diskstats().map(Some)
+    }
+
+    impl Sensor for DiskIoSensor {
+        fn sense(&mut self) -> Result<Vec<Measurement>> {
+            if let Some(ref last_reading) = self.last_reading {
+                wait_for_reading_interval(last_reading.taken_at);
+            }
+            let reading = read_diskstats()?;
+            let mut measurements = Vec::new();
+            if let Some(ref last_reading) = self.last_reading {
+                let elapsed = reading.taken_at.duration_since(last_reading.taken_at);
+                let elapsed_milliseconds = elapsed.as_secs() as f64 * 1000.0 + f64::from(elapsed.subsec_nanos()) / 1e6;
+                for (device, current) in &reading.devices {
+                    if !self.filter.matches(device) || (!self.partitions && is_partition(device)) {
+                        continue;
+                    }
+                    // A new device has nothing to compare against until next time
+                    let previous = match last_reading.devices.get(device) {
+                        Some(previous) => previous,
+                        None => continue,
+                    };
+                    match measurements_between(device, previous, current, elapsed_milliseconds) {
+                        Some(device_measurements) => measurements.extend(device_measurements),
+                        None => info!("Counters for block device {} were reset, skipping it until next time", device),
+                    }
+                }
+            }
+            self.last_reading = Some(reading);
+            Ok(measurements)
+        }
+    }
+
+    // Whole disks are listed in /sys/block, while their partitions only appear inside them
+    fn is_partition(device: &str) -> bool {
+        !Path::new("/sys/block").join(device.replace('/', "!")).exists()
+    }
+
+    // Rates and averages over the time between two readings, the same ones iostat -x reports, or
+    // nothing if the counters started again from zero since last time
+    fn measurements_between(device: &str, previous: &DeviceCounters, current: &DeviceCounters,
+                            elapsed_milliseconds: f64) -> Option<Vec<Measurement>> {
+        if elapsed_milliseconds <= 0.0 {
+            return None;
+        }
+        let reads = current.reads.checked_sub(previous.reads)? as f64;
+        let sectors_read = current.sectors_read.checked_sub(previous.sectors_read)? as f64;
+        let read_milliseconds = current.read_milliseconds.checked_sub(previous.read_milliseconds)? as f64;
+        let writes = current.writes.checked_sub(previous.writes)? as f64;
+        let sectors_written = current.sectors_written.checked_sub(previous.sectors_written)? as f64;
+        let write_milliseconds = current.write_milliseconds.checked_sub(previous.write_milliseconds)? as f64;
+        let io_milliseconds = current.io_milliseconds.checked_sub(previous.io_milliseconds)? as f64;
+        let weighted_io_milliseconds =
+            current.weighted_io_milliseconds.checked_sub(previous.weighted_io_milliseconds)? as f64;
+        let elapsed_seconds = elapsed_milliseconds / 1000.0;
+        // How long each request took on average, from being queued to being done
+        let await_time = |milliseconds: f64, requests: f64| if requests > 0.0 { milliseconds / requests } else { 0.0 };
+        let measurement = |name: &str, value: f64, unit: Unit| {
+            Measurement::new(name, value, MetricKind::Gauge, unit).with_name_tag(DEVICE_TAG, device)
+        };
+        Some(vec![
+            measurement(&READS, reads / elapsed_seconds, Unit::None),
+            measurement(&WRITES, writes / elapsed_seconds, Unit::None),
+            measurement(&READ_BYTES, sectors_read * SECTOR_BYTES / elapsed_seconds, Unit::None),
+            measurement(&WRITTEN_BYTES, sectors_written * SECTOR_BYTES / elapsed_seconds, Unit::None),
+            measurement(&READ_AWAIT, await_time(read_milliseconds, reads), Unit::Milliseconds),
+            measurement(&WRITE_AWAIT, await_time(write_milliseconds, writes), Unit::Milliseconds),
+            measurement(&QUEUE_DEPTH, weighted_io_milliseconds / elapsed_milliseconds, Unit::None),
+            // Busy time can come out a little over the elapsed time since the two aren't read at
+            // exactly the same moment
+            measurement(&UTILIZATION, (io_milliseconds / elapsed_milliseconds * 100.0).min(100.0), Unit::Percent),
+        ])
+    }
+
+    fn read_diskstats() -> Result<Reading> {
+        let mut contents = String::new();
+        File::open("/proc/diskstats")?.read_to_string(&mut contents)?;
+        Ok(Reading { taken_at: Instant::now(), devices: parse_diskstats(&contents)? })
+    }
+
+    // Each line is the major and minor numbers, the device name, then at least 11 counters
+    fn parse_diskstats(contents: &str) -> Result<BTreeMap<String, DeviceCounters>> {
+        let mut devices = BTreeMap::new();
+        for line in contents.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 14 {
+                continue;
+            }
+            let mut counters = [0u64; 11];
+            for (counter, field) in counters.iter_mut().zip(fields[3..].iter()) {
+                *counter = field.parse().map_err(|e| {
+                    Error::new(ErrorKind::InvalidData, format!("Unable to parse /proc/diskstats line '{}': {}", line, e))
+                })?;
+            }
+            devices.insert(fields[2].to_string(), DeviceCounters {
+                reads: counters[0],
+                sectors_read: counters[2],
+                read_milliseconds: counters[3],
+                writes: counters[4],
+                sectors_written: counters[6],
+                write_milliseconds: counters[7],
+                io_milliseconds: counters[9],
+                weighted_io_milliseconds: counters[10],
+            });
+        }
+        Ok(devices)
+    }
+
+    #[test]
+    fn sense_waits_out_a_short_window_after_the_first_reading() {
+        let mut sensor = DiskIoSensor::new(GlobFilter::default(), false).unwrap();
+        let first_taken_at = sensor.last_reading.as_ref().unwrap().taken_at;
+        sensor.sense().unwrap();
+        let second_taken_at = sensor.last_reading.as_ref().unwrap().taken_at;
+        assert!(second_taken_at.duration_since(first_taken_at) >= MIN_READING_INTERVAL);
+    }
+
+    #[test]
+    fn parse_diskstats_reads_counters_for_each_device() {
+        let contents = "   7       0 loop0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0
+ 254       0 vda 6886 5129 2489570 11434 13799 22904 11487192 33236 0 11372 47998 9668 0 9304312 3321 117 6
+ 254       1 vda1 6000 5000 2000000 10000 13000 22000 11000000 33000 0 11000 47000
+";
+        let devices = parse_diskstats(contents).unwrap();
+        assert_eq!(devices.keys().collect::<Vec<_>>(), vec!["loop0", "vda", "vda1"]);
+        assert_eq!(devices["vda"], DeviceCounters {
+            reads: 6886,
+            sectors_read: 2489570,
+            read_milliseconds: 11434,
+            writes: 13799,
+            sectors_written: 11487192,
+            write_milliseconds: 33236,
+            io_milliseconds: 11372,
+            weighted_io_milliseconds: 47998,
+        });
+    }
+
+    #[test]
+    fn measurements_between_computes_rates_await_and_utilization() {
+        let previous = DeviceCounters {
+            reads: 100,
+            sectors_read: 1000,
+            read_milliseconds: 500,
+            writes: 50,
+            sectors_written: 800,
+            write_milliseconds: 300,
+            io_milliseconds: 1000,
+            weighted_io_milliseconds: 2000,
+        };
+        let current = DeviceCounters {
+            reads: 300,
+            sectors_read: 5000,
+            read_milliseconds: 1500,
+            writes: 50,
+            sectors_written: 800,
+            write_milliseconds: 300,
+            io_milliseconds: 1500,
+            weighted_io_milliseconds: 3000,
+        };
+        let measurements = measurements_between("vda", &previous, &current, 2000.0).unwrap();
+        let value = |name: &str| measurements.iter().find(|measurement| measurement.name == name).unwrap().value;
+        assert_eq!(value("disk_io.reads_per_second"), 100.0);
+        assert_eq!(value("disk_io.read_bytes_per_second"), 1024000.0);
+        assert_eq!(value("disk_io.read_await"), 5.0);
+        assert_eq!(value("disk_io.write_await"), 0.0);
+        assert_eq!(value("disk_io.queue_depth"), 0.5);
+        assert_eq!(value("disk_io.utilization"), 25.0);
+        assert!(measurements_between("vda", &current, &previous, 2000.0).is_none());
+    }
+}
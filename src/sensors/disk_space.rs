@@ -1,7 +1,4 @@
-extern crate cadence;
-
 use std::ffi::OsString;
-use std::i64;
 use super::Sensor;
 
 pub struct DiskSpaceSensor {
@@ -21,21 +18,22 @@ mod platform {
 
     use super::DiskSpaceSensor;
     use super::Sensor;
-    use cadence::prelude::*;
-    use cadence::StatsdClient;
+    use measurement::{Measurement, MetricKind, Unit};
     use std::os::windows::prelude::*;
-    use std::io::Error;
+    use std::io::{Error, Result};
     use self::winapi::um::winnt::LPCWSTR;
     use std::ptr;
 
     static FALSE: i32 = 0;
-    static FATAL_ERROR: &str = "Fatal error counting metric";
     static METRICS_PREFIX: &str = "drive";
-    static TOTAL_BYTES: &str = "total_bytes";
-    static FREE_BYTES: &str = "free_bytes";
+    static MOUNT_TAG: &str = "mount";
+    lazy_static! {
+        static ref TOTAL_BYTES: String = METRICS_PREFIX.to_string() + ".total_bytes";
+        static ref FREE_BYTES: String = METRICS_PREFIX.to_string() + ".free_bytes";
+    }
 
     impl Sensor for DiskSpaceSensor {
-        fn sense(&mut self, statsd_client: &StatsdClient) {
+        fn sense(&mut self) -> Result<Vec<Measurement>> {
             let mut total_accessible_drive_size_bytes: u64 = 0;
             let mut total_free_drive_space_bytes: u64 = 0;
             let wide_vec: Vec<u16> = self.directory_on_disk.encode_wide().collect();
@@ -47,27 +45,24 @@ mod platform {
                         &mut total_free_drive_space_bytes as *mut u64)
             };
             if return_code == FALSE {
-                error!("Error getting drive usage for drive '{}': {}",
-                       self.directory_on_disk.to_string_lossy(), Error::last_os_error());
+                let error = Error::last_os_error();
+                Err(Error::new(error.kind(), format!("Error getting drive usage for drive '{}': {}",
+                                                     self.directory_on_disk.to_string_lossy(), error)))
             } else {
                 info!("'{}' total size: {} GiB", self.directory_on_disk.to_string_lossy(),
                       total_accessible_drive_size_bytes / 1024 / 1024 / 1024);
                 info!("'{}' free size: {} GiB", self.directory_on_disk.to_string_lossy(),
                       total_free_drive_space_bytes / 1024 / 1024 / 1024);
                 let directory_name = self.directory_on_disk.to_string_lossy();
-                statsd_client.count(&create_drive_metric_name(&directory_name, TOTAL_BYTES),
-                                    super::value_or_max(total_accessible_drive_size_bytes))
-                    .expect(FATAL_ERROR);
-                statsd_client.count(&create_drive_metric_name(&directory_name, FREE_BYTES),
-                                    super::value_or_max(total_free_drive_space_bytes))
-                    .expect(FATAL_ERROR);
+                Ok(vec![
+                    Measurement::new(&TOTAL_BYTES, total_accessible_drive_size_bytes as f64, MetricKind::Gauge, Unit::Bytes)
+                        .with_name_tag(MOUNT_TAG, &directory_name),
+                    Measurement::new(&FREE_BYTES, total_free_drive_space_bytes as f64, MetricKind::Gauge, Unit::Bytes)
+                        .with_name_tag(MOUNT_TAG, &directory_name),
+                ])
             }
         }
     }
-
-    fn create_drive_metric_name(drive: &str, suffix: &str) -> String {
-        METRICS_PREFIX.to_string() + "." + &drive.replace(":", "") + "." + suffix
-    }
 }
 
 #[cfg(target_os="linux")]
@@ -78,57 +73,208 @@ mod platform {
     use std::os::unix::prelude::*;
     use std::ffi::CString;
     use self::libc::statvfs64;
-    use cadence::prelude::*;
-    use cadence::StatsdClient;
+    use measurement::{Measurement, MetricKind, Unit};
+    use std::fs::{self, File};
     use std::mem;
-    use std::io::Error;
+    use std::io::{BufRead, BufReader, Error, ErrorKind, Result};
+    use std::path::{Path, PathBuf};
 
     const FALSE: i32 = 0;
-    const FATAL_ERROR: &'static str = "Fatal error counting metric";
-    const METRICS_PREFIX: &'static str = "drive";
-    static TOTAL_BYTES: &str = "total_bytes";
-    static FREE_BYTES: &str = "free_bytes";
+    const METRICS_PREFIX: &str = "drive";
+    static PATH_TAG: &str = "path";
+    static MOUNT_TAG: &str = "mount";
+    static DEVICE_TAG: &str = "device";
+    lazy_static! {
+        static ref TOTAL_BYTES: String = METRICS_PREFIX.to_string() + ".total_bytes";
+        static ref FREE_BYTES: String = METRICS_PREFIX.to_string() + ".free_bytes";
+        static ref AVAILABLE_BYTES: String = METRICS_PREFIX.to_string() + ".available_bytes";
+        static ref USED_PERCENT: String = METRICS_PREFIX.to_string() + ".used_percent";
+        static ref TOTAL_INODES: String = METRICS_PREFIX.to_string() + ".total_inodes";
+        static ref FREE_INODES: String = METRICS_PREFIX.to_string() + ".free_inodes";
+        static ref AVAILABLE_INODES: String = METRICS_PREFIX.to_string() + ".available_inodes";
+        static ref INODES_USED_PERCENT: String = METRICS_PREFIX.to_string() + ".inodes_used_percent";
+    }
+
+    /// Space and inodes on a filesystem. Free counts include what is reserved for root, while
+    /// available counts are what everyone else can still use.
+    struct DiskUsage {
+        total_bytes: u64,
+        free_bytes: u64,
+        available_bytes: u64,
+        total_inodes: u64,
+        free_inodes: u64,
+        available_inodes: u64,
+    }
+
+    impl DiskUsage {
+        // Block counts are in fragments of f_frsize bytes. f_bsize is only the preferred I/O size,
+        // which can be bigger.
+        fn from_statvfs(info_struct: &statvfs64) -> DiskUsage {
+            DiskUsage {
+                total_bytes: info_struct.f_frsize * info_struct.f_blocks,
+                free_bytes: info_struct.f_frsize * info_struct.f_bfree,
+                available_bytes: info_struct.f_frsize * info_struct.f_bavail,
+                total_inodes: info_struct.f_files,
+                free_inodes: info_struct.f_ffree,
+                available_inodes: info_struct.f_favail,
+            }
+        }
+
+        fn measurements(&self) -> Vec<Measurement> {
+            let gauge = |name: &str, value: u64, unit: Unit| Measurement::new(name, value as f64, MetricKind::Gauge, unit);
+            let mut measurements = vec![
+                gauge(&TOTAL_BYTES, self.total_bytes, Unit::Bytes),
+                gauge(&FREE_BYTES, self.free_bytes, Unit::Bytes),
+                gauge(&AVAILABLE_BYTES, self.available_bytes, Unit::Bytes),
+            ];
+            if let Some(used_percent) = used_percent(self.total_bytes, self.free_bytes, self.available_bytes) {
+                measurements.push(Measurement::new(&USED_PERCENT, used_percent, MetricKind::Gauge, Unit::Percent));
+            }
+            // Some filesystems, like btrfs and vfat, have no fixed number of inodes and report zero
+            if self.total_inodes > 0 {
+                measurements.push(gauge(&TOTAL_INODES, self.total_inodes, Unit::None));
+                measurements.push(gauge(&FREE_INODES, self.free_inodes, Unit::None));
+                measurements.push(gauge(&AVAILABLE_INODES, self.available_inodes, Unit::None));
+                if let Some(used_percent) = used_percent(self.total_inodes, self.free_inodes, self.available_inodes) {
+                    measurements.push(Measurement::new(&INODES_USED_PERCENT, used_percent, MetricKind::Gauge, Unit::Percent));
+                }
+            }
+            measurements
+        }
+    }
+
+    // The way df works it out, as a share of what unprivileged users can have rather than of the
+    // total, so that 100% means they can't write any more even though root still can
+    fn used_percent(total: u64, free: u64, available: u64) -> Option<f64> {
+        let used = total.saturating_sub(free);
+        let usable = used + available;
+        if usable == 0 {
+            None
+        } else {
+            Some(used as f64 / usable as f64 * 100.0)
+        }
+    }
 
     impl Sensor for DiskSpaceSensor {
-        fn sense(&mut self, statsd_client: &StatsdClient) {
+        fn sense(&mut self) -> Result<Vec<Measurement>> {
             let dir_on_drive = CString::new(self.directory_on_disk.as_bytes()).unwrap();
             let mut info_struct: statvfs64 = unsafe { mem::zeroed() };
             let return_code: i32 = unsafe {
                 libc::statvfs64(dir_on_drive.as_ptr(), &mut info_struct)
             };
             if return_code == FALSE {
-                let total_accessible_drive_size_bytes = info_struct.f_frsize * info_struct.f_blocks;
-                let total_free_drive_space_bytes = info_struct.f_bsize * info_struct.f_bfree;
+                let usage = DiskUsage::from_statvfs(&info_struct);
                 info!("'{}' total size: {} GiB", self.directory_on_disk.to_string_lossy(),
-                      total_accessible_drive_size_bytes / 1024 / 1024 / 1024);
+                      usage.total_bytes / 1024 / 1024 / 1024);
                 info!("'{}' free size: {} GiB", self.directory_on_disk.to_string_lossy(),
-                      total_free_drive_space_bytes / 1024 / 1024 / 1024);
+                      usage.free_bytes / 1024 / 1024 / 1024);
 
-                let directory_name = self.directory_on_disk.to_string_lossy();
-                statsd_client.count(&create_drive_metric_name(&directory_name, TOTAL_BYTES),
-                                    super::value_or_max(total_accessible_drive_size_bytes))
-                    .expect(FATAL_ERROR);
-                statsd_client.count(&create_drive_metric_name(&directory_name, FREE_BYTES),
-                                    super::value_or_max(total_free_drive_space_bytes))
-                    .expect(FATAL_ERROR);
+                let (device, mount_point) = match mount_for_path(Path::new(&self.directory_on_disk)) {
+                    Ok(mount) => mount,
+                    Err(e) => {
+                        debug!("Couldn't find the mount for '{}', tagging with the path instead: {}",
+                               self.directory_on_disk.to_string_lossy(), e);
+                        (None, self.directory_on_disk.to_string_lossy().into_owned())
+                    }
+                };
+                // Drive names have always had the configured path in them rather than the mount point
+                let tag = |measurement: Measurement| {
+                    let measurement = measurement
+                        .with_name_tag(PATH_TAG, &self.directory_on_disk.to_string_lossy())
+                        .with_tag(MOUNT_TAG, &mount_point);
+                    match device {
+                        Some(ref device) => measurement.with_tag(DEVICE_TAG, device),
+                        None => measurement,
+                    }
+                };
+                Ok(usage.measurements().into_iter().map(tag).collect())
             } else {
-                error!("Error getting drive usage for drive '{}': {}",
-                       self.directory_on_disk.to_string_lossy(), Error::last_os_error());
+                let error = Error::last_os_error();
+                Err(Error::new(error.kind(), format!("Error getting drive usage for drive '{}': {}",
+                                                     self.directory_on_disk.to_string_lossy(), error)))
             }
         }
     }
 
-    fn create_drive_metric_name(drive: &str, suffix: &str) -> String {
-        METRICS_PREFIX.to_string() + "." + &drive.replace(":", "") + "." + suffix
+    // Finds the device and mount point that a path lives on from the kernel's mount table, which
+    // is the deepest mount point that contains the path
+    fn mount_for_path(path: &Path) -> Result<(Option<String>, String)> {
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        let mounts = File::open("/proc/self/mounts")?;
+        let mut best_match: Option<(String, PathBuf)> = None;
+        for line in BufReader::new(mounts).lines() {
+            let line = line?;
+            let mut fields = line.split_whitespace();
+            let (device, mount_point) = match (fields.next(), fields.next()) {
+                (Some(device), Some(mount_point)) => (unescape_mount_field(device), PathBuf::from(unescape_mount_field(mount_point))),
+                _ => continue,
+            };
+            let deeper = match best_match {
+                Some((_, ref best_mount_point)) => mount_point.components().count() >= best_mount_point.components().count(),
+                None => true,
+            };
+            if path.starts_with(&mount_point) && deeper {
+                best_match = Some((device, mount_point));
+            }
+        }
+        match best_match {
+            Some((device, mount_point)) => Ok((Some(device), mount_point.to_string_lossy().into_owned())),
+            None => Err(Error::new(ErrorKind::NotFound, format!("No mount contains '{}'", path.display()))),
+        }
+    }
+
+    // The mount table escapes whitespace and backslashes in its fields as three-digit octal codes
+    fn unescape_mount_field(field: &str) -> String {
+        let mut unescaped = String::new();
+        let mut rest = field;
+        while let Some(index) = rest.find('\\') {
+            unescaped.push_str(&rest[..index]);
+            let code = rest.get(index + 1..index + 4).and_then(|digits| u8::from_str_radix(digits, 8).ok());
+            match code {
+                Some(code) => {
+                    unescaped.push(code as char);
+                    rest = &rest[index + 4..];
+                },
+                None => {
+                    unescaped.push('\\');
+                    rest = &rest[index + 1..];
+                }
+            }
+        }
+        unescaped.push_str(rest);
+        unescaped
+    }
+
+    #[test]
+    fn unescape_mount_field_decodes_octal_escapes() {
+        assert_eq!(unescape_mount_field(r"/mnt/my\040disk"), "/mnt/my disk");
+        assert_eq!(unescape_mount_field("/dev/sda1"), "/dev/sda1");
+    }
+
+    #[test]
+    fn measurements_include_available_space_inodes_and_used_percentages() {
+        let usage = DiskUsage {
+            total_bytes: 1000,
+            free_bytes: 300,
+            available_bytes: 200,
+            total_inodes: 100,
+            free_inodes: 0,
+            available_inodes: 0,
+        };
+        let measurements = usage.measurements();
+        let value = |name: &str| measurements.iter().find(|measurement| measurement.name == name).map(|measurement| measurement.value);
+        assert_eq!(value("drive.available_bytes"), Some(200.0));
+        assert_eq!(value("drive.used_percent"), Some(700.0 / 900.0 * 100.0));
+        assert_eq!(value("drive.total_inodes"), Some(100.0));
+        assert_eq!(value("drive.inodes_used_percent"), Some(100.0));
+        let no_inodes = DiskUsage { total_inodes: 0, ..usage };
+        assert_eq!(no_inodes.measurements().len(), 4);
     }
-}
 
-fn value_or_max(value: u64) -> i64 {
-    if value >= i64::MAX as u64 {
-        warn!("Value {} larger than max value of {}, reporting max value {} instead",
-                value, i64::MAX, i64::MAX);
-        i64::MAX
-    } else {
-        value as i64
+    #[test]
+    fn mount_for_path_finds_root_mount() {
+        let (device, mount_point) = mount_for_path(Path::new("/")).unwrap();
+        assert!(device.is_some());
+        assert_eq!(mount_point, "/");
     }
 }
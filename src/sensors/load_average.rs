@@ -0,0 +1,128 @@
+use super::Sensor;
+use std::io::Result;
+
+pub struct LoadAverageSensor {
+}
+
+impl LoadAverageSensor {
+    /// Fails on platforms that have no load average, so that configuring one stops the agent up
+    /// front rather than failing on every run
+    pub fn new() -> Result<LoadAverageSensor> {
+        platform::check_supported()?;
+        Ok(LoadAverageSensor {})
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use super::Sensor;
+    use super::LoadAverageSensor;
+    use measurement::Measurement;
+    use std::io::{Error, ErrorKind, Result};
+
+    // Windows has processor queue length counters, but nothing that means the same as a load average
+    pub fn check_supported() -> Result<()> {
+        Err(Error::new(ErrorKind::InvalidInput, "The load_average sensor is only available on Linux"))
+    }
+
+    impl Sensor for LoadAverageSensor {
+        fn sense(&mut self) -> Result<Vec<Measurement>> {
+            check_supported().map(|_| Vec::new())
+        }
+    }
+}
+
+#[cfg(target_os="linux")]
+mod platform {
+    extern crate libc;
+
+    use super::Sensor;
+    use super::LoadAverageSensor;
+    use measurement::{Measurement, MetricKind, Unit};
+    use std::fs::File;
+    use std::io::{Error, ErrorKind, Result};
+    use std::io::prelude::*;
+
+    const METRICS_PREFIX: &str = "load_average";
+    const PERIOD_TAG: &str = "period";
+    const PERIODS: [&str; 3] = ["1m", "5m", "15m"];
+    lazy_static! {
+        static ref LOAD: String = METRICS_PREFIX.to_string() + ".load";
+        static ref LOAD_PER_CPU: String = METRICS_PREFIX.to_string() + ".load_per_cpu";
+        static ref ONLINE_CPUS: String = METRICS_PREFIX.to_string() + ".online_cpus";
+        static ref RUNNABLE_TASKS: String = METRICS_PREFIX.to_string() + ".runnable_tasks";
+        static ref TOTAL_TASKS: String = METRICS_PREFIX.to_string() + ".total_tasks";
+        static ref LAST_PID: String = METRICS_PREFIX.to_string() + ".last_pid";
+    }
+
+    pub fn check_supported() -> Result<()> {
+        read_loadavg().map(|_| ())
+    }
+
+    /// What /proc/loadavg says
+    #[derive(Debug, PartialEq)]
+    struct LoadAverage {
+        loads: [f64; 3],
+        runnable_tasks: u64,
+        total_tasks: u64,
+        last_pid: u64,
+    }
+
+    impl Sensor for LoadAverageSensor {
+        fn sense(&mut self) -> Result<Vec<Measurement>> {
+            let load_average = read_loadavg()?;
+            // Checked every time since CPUs can be brought online and offline while we run
+            let online_cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+            if online_cpus < 1 {
+                let error = Error::last_os_error();
+                return Err(Error::new(error.kind(), format!("Error getting the number of online CPUs: {}", error)));
+            }
+            let mut measurements = Vec::new();
+            for (period, &load) in PERIODS.iter().zip(load_average.loads.iter()) {
+                measurements.push(Measurement::new(&LOAD, load, MetricKind::Gauge, Unit::None)
+                    .with_name_tag(PERIOD_TAG, period));
+                // Load is a count of tasks, so how busy it means the machine is depends on how many
+                // CPUs there are to run them on
+                measurements.push(Measurement::new(&LOAD_PER_CPU, load / online_cpus as f64, MetricKind::Gauge, Unit::None)
+                    .with_name_tag(PERIOD_TAG, period));
+            }
+            measurements.push(Measurement::new(&ONLINE_CPUS, online_cpus as f64, MetricKind::Gauge, Unit::None));
+            measurements.push(Measurement::new(&RUNNABLE_TASKS, load_average.runnable_tasks as f64, MetricKind::Gauge, Unit::None));
+            measurements.push(Measurement::new(&TOTAL_TASKS, load_average.total_tasks as f64, MetricKind::Gauge, Unit::None));
+            measurements.push(Measurement::new(&LAST_PID, load_average.last_pid as f64, MetricKind::Gauge, Unit::None));
+            Ok(measurements)
+        }
+    }
+
+    fn read_loadavg() -> Result<LoadAverage> {
+        let mut contents = String::new();
+        File::open("/proc/loadavg")?.read_to_string(&mut contents)?;
+        parse_loadavg(&contents)
+    }
+
+    // Looks like `0.20 0.18 0.12 1/80 11206`: three load averages, runnable and total tasks, and
+    // the most recently used PID
+    fn parse_loadavg(contents: &str) -> Result<LoadAverage> {
+        let invalid = || Error::new(ErrorKind::InvalidData, format!("Unexpected /proc/loadavg contents '{}'", contents.trim()));
+        let fields: Vec<&str> = contents.split_whitespace().collect();
+        if fields.len() < 5 {
+            return Err(invalid());
+        }
+        let mut loads = [0.0; 3];
+        for (load, field) in loads.iter_mut().zip(fields.iter()) {
+            *load = field.parse().map_err(|_| invalid())?;
+        }
+        let mut tasks = fields[3].splitn(2, '/');
+        let runnable_tasks = tasks.next().and_then(|tasks| tasks.parse().ok()).ok_or_else(invalid)?;
+        let total_tasks = tasks.next().and_then(|tasks| tasks.parse().ok()).ok_or_else(invalid)?;
+        let last_pid = fields[4].parse().map_err(|_| invalid())?;
+        Ok(LoadAverage { loads, runnable_tasks, total_tasks, last_pid })
+    }
+
+    #[test]
+    fn parse_loadavg_reads_loads_tasks_and_last_pid() {
+        assert_eq!(parse_loadavg("0.20 0.18 0.12 1/80 11206\n").unwrap(),
+                   LoadAverage { loads: [0.20, 0.18, 0.12], runnable_tasks: 1, total_tasks: 80, last_pid: 11206 });
+        assert!(parse_loadavg("0.20 0.18 0.12 80 11206\n").is_err());
+    }
+}
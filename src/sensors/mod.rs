@@ -1,9 +1,36 @@
+pub mod disk_io;
 pub mod disk_space;
 pub mod physical_memory;
 pub mod cpu_time;
+pub mod load_average;
+pub mod network_interfaces;
+pub mod registry;
 
 use super::Sensor;
+#[cfg(target_os="linux")]
+use std::thread;
+#[cfg(target_os="linux")]
+use std::time::{Duration, Instant};
 
+// Counters read closer together than this are too few ticks apart to say much, which happens when
+// the first reading comes straight after the sensor is built
+#[cfg(target_os="linux")]
+static MIN_READING_INTERVAL: Duration = Duration::from_millis(250);
+
+pub type DiskIoSensor = self::disk_io::DiskIoSensor;
 pub type DiskSpaceSensor = self::disk_space::DiskSpaceSensor;
 pub type PhysicalMemorySensor = self::physical_memory::PhysicalMemorySensor;
-pub type CpuTimeSensor = self::cpu_time::CpuTimeSensor;
\ No newline at end of file
+pub type CpuTimeSensor = self::cpu_time::CpuTimeSensor;
+pub type LoadAverageSensor = self::load_average::LoadAverageSensor;
+pub type NetworkInterfaceSensor = self::network_interfaces::NetworkInterfaceSensor;
+pub type SensorConfig = self::registry::SensorConfig;
+
+/// Waits until at least the minimum interval has passed since the last reading, so that rates
+/// worked out between the two aren't thrown off by a window only a few ticks long
+#[cfg(target_os="linux")]
+fn wait_for_reading_interval(last_read: Instant) {
+    let since_last_read = last_read.elapsed();
+    if since_last_read < MIN_READING_INTERVAL {
+        thread::sleep(MIN_READING_INTERVAL - since_last_read);
+    }
+}
@@ -0,0 +1,217 @@
+use super::Sensor;
+use filter::GlobFilter;
+use std::io::Result;
+
+pub struct NetworkInterfaceSensor {
+    // Which interfaces to report on, by name
+    filter: GlobFilter,
+    last_reading: Option<platform::Reading>,
+}
+
+impl NetworkInterfaceSensor {
+    /// Fails on platforms we can't read interface counters on, so that configuring one stops the
+    /// agent up front rather than failing on every run
+    pub fn new(filter: GlobFilter) -> Result<NetworkInterfaceSensor> {
+        let last_reading = platform::first_reading()?;
+        Ok(NetworkInterfaceSensor { filter, last_reading })
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use super::Sensor;
+    use super::NetworkInterfaceSensor;
+    use measurement::Measurement;
+    use std::io::{Error, ErrorKind, Result};
+
+    pub enum Reading {}
+
+    pub fn first_reading() -> Result<Option<Reading>> {
+        Err(Error::new(ErrorKind::InvalidInput, "The network_interfaces sensor is only available on Linux"))
+    }
+
+    impl Sensor for NetworkInterfaceSensor {
+        fn sense(&mut self) -> Result<Vec<Measurement>> {
+            first_reading().map(|_| Vec::new())
+        }
+    }
+}
+
+#[cfg(target_os="linux")]
+mod platform {
+    use super::Sensor;
+    use super::NetworkInterfaceSensor;
+    use measurement::{Measurement, MetricKind, Unit};
+    use sensors::wait_for_reading_interval;
+    use std::collections::BTreeMap;
+    use std::fs::{self, File};
+    use std::io::{Error, ErrorKind, Result};
+    use std::io::prelude::*;
+    use std::time::Instant;
+    #[cfg(test)]
+    use filter::GlobFilter;
+    #[cfg(test)]
+    use sensors::MIN_READING_INTERVAL;
+
+    const METRICS_PREFIX: &str = "network";
+    const INTERFACE_TAG: &str = "interface";
+    // The columns of /proc/net/dev we report, by their position after the interface name
+    const COUNTERS: [(&str, usize); 8] = [
+        ("receive_bytes", 0),
+        ("receive_packets", 1),
+        ("receive_errors", 2),
+        ("receive_drops", 3),
+        ("transmit_bytes", 8),
+        ("transmit_packets", 9),
+        ("transmit_errors", 10),
+        ("transmit_drops", 11),
+    ];
+    lazy_static! {
+        static ref RATE_NAMES: Vec<String> = COUNTERS
+            .iter()
+            .map(|&(counter, _)| METRICS_PREFIX.to_string() + "." + counter + "_per_second")
+            .collect();
+    }
+
+    type Counters = [u64; 8];
+
+    /// One interface's counters, along with the index the kernel gave it. An interface that is
+    /// deleted and created again under the same name gets a new index and starts counting from zero.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct InterfaceCounters {
+        ifindex: Option<u64>,
+        counters: Counters,
+    }
+
+    pub struct Reading {
+        taken_at: Instant,
+        interfaces: BTreeMap<String, InterfaceCounters>,
+    }
+
+    pub fn first_reading() -> Result<Option<Reading>> {
+        read_interfaces().map(Some)
+    }
+
+    impl Sensor for NetworkInterfaceSensor {
+        fn sense(&mut self) -> Result<Vec<Measurement>> {
+            if let Some(ref last_reading) = self.last_reading {
+                wait_for_reading_interval(last_reading.taken_at);
+            }
+            let reading = read_interfaces()?;
+            let mut measurements = Vec::new();
+            if let Some(ref last_reading) = self.last_reading {
+                let elapsed = reading.taken_at.duration_since(last_reading.taken_at);
+                let elapsed_seconds = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+                for (interface, current) in &reading.interfaces {
+                    if !self.filter.matches(interface) {
+                        continue;
+                    }
+                    // A new interface has nothing to compare against until next time
+                    let previous = match last_reading.interfaces.get(interface) {
+                        Some(previous) => previous,
+                        None => continue,
+                    };
+                    match rates(previous, current, elapsed_seconds) {
+                        Some(rates) => {
+                            for (name, &rate) in RATE_NAMES.iter().zip(rates.iter()) {
+                                measurements.push(Measurement::new(name, rate, MetricKind::Gauge, Unit::None)
+                                    .with_name_tag(INTERFACE_TAG, interface));
+                            }
+                        },
+                        None => info!("Counters for network interface {} were reset, skipping it until next time", interface),
+                    }
+                }
+            }
+            self.last_reading = Some(reading);
+            Ok(measurements)
+        }
+    }
+
+    // Per second rates of change, or nothing if the counters started again from zero since last time
+    fn rates(previous: &InterfaceCounters, current: &InterfaceCounters, elapsed_seconds: f64) -> Option<[f64; 8]> {
+        if elapsed_seconds <= 0.0 || previous.ifindex != current.ifindex {
+            return None;
+        }
+        let mut rates = [0.0; 8];
+        for (index, rate) in rates.iter_mut().enumerate() {
+            let delta = current.counters[index].checked_sub(previous.counters[index])?;
+            *rate = delta as f64 / elapsed_seconds;
+        }
+        Some(rates)
+    }
+
+    fn read_interfaces() -> Result<Reading> {
+        let mut contents = String::new();
+        File::open("/proc/net/dev")?.read_to_string(&mut contents)?;
+        let taken_at = Instant::now();
+        let mut interfaces = parse_net_dev(&contents)?;
+        for (interface, counters) in &mut interfaces {
+            counters.ifindex = read_ifindex(interface);
+        }
+        Ok(Reading { taken_at, interfaces })
+    }
+
+    // Only used to spot interfaces being recreated, so an interface that has gone from /sys by
+    // the time we look just doesn't get that check
+    fn read_ifindex(interface: &str) -> Option<u64> {
+        fs::read_to_string(format!("/sys/class/net/{}/ifindex", interface))
+            .ok()
+            .and_then(|ifindex| ifindex.trim().parse().ok())
+    }
+
+    // Two header lines, then `name: counters...` for each interface. Large receive byte counts can
+    // run into the colon with no space in between.
+    fn parse_net_dev(contents: &str) -> Result<BTreeMap<String, InterfaceCounters>> {
+        let mut interfaces = BTreeMap::new();
+        for line in contents.lines().skip(2) {
+            let colon = match line.find(':') {
+                Some(colon) => colon,
+                None => continue,
+            };
+            let interface = line[..colon].trim();
+            let fields: Vec<&str> = line[colon + 1..].split_whitespace().collect();
+            let mut counters: Counters = [0; 8];
+            for (counter, &(counter_name, column)) in counters.iter_mut().zip(COUNTERS.iter()) {
+                *counter = fields.get(column).and_then(|field| field.parse().ok()).ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidData,
+                               format!("Unable to read {} for interface {} from /proc/net/dev line '{}'",
+                                       counter_name, interface, line))
+                })?;
+            }
+            interfaces.insert(interface.to_string(), InterfaceCounters { ifindex: None, counters });
+        }
+        Ok(interfaces)
+    }
+
+    #[test]
+    fn sense_waits_out_a_short_window_after_the_first_reading() {
+        let mut sensor = NetworkInterfaceSensor::new(GlobFilter::default()).unwrap();
+        let first_taken_at = sensor.last_reading.as_ref().unwrap().taken_at;
+        sensor.sense().unwrap();
+        let second_taken_at = sensor.last_reading.as_ref().unwrap().taken_at;
+        assert!(second_taken_at.duration_since(first_taken_at) >= MIN_READING_INTERVAL);
+    }
+
+    #[test]
+    fn parse_net_dev_reads_counters_for_each_interface() {
+        let contents = "Inter-|   Receive                                                |  Transmit
+ face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed
+    lo: 57184103    8802    0    0    0     0          0         0 57184103    8802    0    0    0     0       0          0
+  eth0:12345678901     240    1    2    0     0          0         0    22813     217    3    4    0     0       0          0
+";
+        let interfaces = parse_net_dev(contents).unwrap();
+        assert_eq!(interfaces.keys().collect::<Vec<_>>(), vec!["eth0", "lo"]);
+        assert_eq!(interfaces["eth0"].counters, [12345678901, 240, 1, 2, 22813, 217, 3, 4]);
+    }
+
+    #[test]
+    fn rates_skip_interfaces_whose_counters_were_reset() {
+        let previous = InterfaceCounters { ifindex: Some(2), counters: [1000, 10, 0, 0, 500, 5, 0, 0] };
+        let current = InterfaceCounters { ifindex: Some(2), counters: [3000, 30, 0, 2, 900, 9, 0, 0] };
+        assert_eq!(rates(&previous, &current, 2.0), Some([1000.0, 10.0, 0.0, 1.0, 200.0, 2.0, 0.0, 0.0]));
+        let recreated = InterfaceCounters { ifindex: Some(7), ..current.clone() };
+        assert_eq!(rates(&previous, &recreated, 2.0), None);
+        let wrapped = InterfaceCounters { ifindex: Some(2), counters: [10, 1, 0, 0, 900, 9, 0, 0] };
+        assert_eq!(rates(&previous, &wrapped, 2.0), None);
+    }
+}
@@ -1,11 +1,14 @@
-extern crate cadence;
-
 use super::Sensor;
-use std::i64;
 
 pub struct PhysicalMemorySensor {
 }
 
+impl Default for PhysicalMemorySensor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PhysicalMemorySensor {
     pub fn new() -> PhysicalMemorySensor {
         PhysicalMemorySensor {}
@@ -19,16 +22,14 @@ mod platform {
 
     use super::Sensor;
     use super::PhysicalMemorySensor;
-    use cadence::prelude::*;
-    use cadence::StatsdClient;
+    use measurement::{Measurement, MetricKind, Unit};
     use std::mem;
     use self::winapi::um::sysinfoapi::MEMORYSTATUSEX;
     use self::winapi::um::sysinfoapi;
-    use std::io::Error;
+    use std::io::{Error, Result};
 
     const FALSE: i32 = 0;
-    const FATAL_ERROR: &'static str = "Fatal error counting metric";
-    const METRICS_PREFIX: &'static str = "physical_memory";
+    const METRICS_PREFIX: &str = "physical_memory";
     lazy_static! {
         static ref TOTAL_BYTES: String = METRICS_PREFIX.to_string() + ".total_bytes";
         static ref FREE_BYTES: String = METRICS_PREFIX.to_string() + ".free_bytes";
@@ -36,23 +37,23 @@ mod platform {
     }
 
     impl Sensor for PhysicalMemorySensor {
-        fn sense(&mut self, statsd_client: &StatsdClient) {
+        fn sense(&mut self) -> Result<Vec<Measurement>> {
             let mut info_struct: MEMORYSTATUSEX = unsafe { mem::zeroed() };
             info_struct.dwLength = mem::size_of::<MEMORYSTATUSEX>() as u32;
             let return_code: i32 = unsafe { sysinfoapi::GlobalMemoryStatusEx(&mut info_struct as *mut MEMORYSTATUSEX) };
             if return_code == FALSE {
-                error!("Error getting physical memory usage: {}", Error::last_os_error());
+                let error = Error::last_os_error();
+                Err(Error::new(error.kind(), format!("Error getting physical memory usage: {}", error)))
             } else {
                 let total_accessible_bytes = info_struct.ullTotalPhys;
                 let total_free_bytes = info_struct.ullAvailPhys;
                 info!("Total accessible physical memory: {} MiB", total_accessible_bytes / 1024 / 1024);
                 info!("Total free physical memory: {} MiB", total_free_bytes / 1024 / 1024);
-                statsd_client.count(&TOTAL_BYTES, super::value_or_max(total_accessible_bytes))
-                    .expect(FATAL_ERROR);
-                statsd_client.count(&FREE_BYTES, super::value_or_max(total_free_bytes))
-                    .expect(FATAL_ERROR);
-                statsd_client.count(&AVAILABLE_BYTES, super::value_or_max(total_free_bytes))
-                    .expect(FATAL_ERROR);
+                Ok(vec![
+                    Measurement::new(&TOTAL_BYTES, total_accessible_bytes as f64, MetricKind::Gauge, Unit::Bytes),
+                    Measurement::new(&FREE_BYTES, total_free_bytes as f64, MetricKind::Gauge, Unit::Bytes),
+                    Measurement::new(&AVAILABLE_BYTES, total_free_bytes as f64, MetricKind::Gauge, Unit::Bytes),
+                ])
             }
         }
     }
@@ -65,8 +66,7 @@ mod platform {
 
     use super::Sensor;
     use super::PhysicalMemorySensor;
-    use cadence::prelude::*;
-    use cadence::StatsdClient;
+    use measurement::{Measurement, MetricKind, Unit};
     use std::mem;
     use std::io::{Error, ErrorKind, Result};
     use std::fs::File;
@@ -75,8 +75,7 @@ mod platform {
     use self::regex::Regex;
 
     const FALSE: i32 = 0;
-    const FATAL_ERROR: &'static str = "Fatal error counting metric";
-    const METRICS_PREFIX: &'static str = "physical_memory";
+    const METRICS_PREFIX: &str = "physical_memory";
     lazy_static! {
         static ref TOTAL_BYTES: String = METRICS_PREFIX.to_string() + ".total_bytes";
         static ref FREE_BYTES: String = METRICS_PREFIX.to_string() + ".free_bytes";
@@ -85,15 +84,8 @@ mod platform {
     }
 
     impl Sensor for PhysicalMemorySensor {
-        fn sense(&mut self, statsd_client: &StatsdClient) {
-            let available_mem_in_kb;
-            match available_memory_from_meminfo() {
-                Err(e) => {
-                    error!("Error getting available memory from meminfo: {:?}", e);
-                    return
-                },
-                Ok(available_kb) => available_mem_in_kb = available_kb
-            }
+        fn sense(&mut self) -> Result<Vec<Measurement>> {
+            let available_mem_in_kb = available_memory_from_meminfo()?;
             let mut info_struct: libc::sysinfo = unsafe { mem::zeroed() };
             let return_code = unsafe { libc::sysinfo(&mut info_struct as *mut libc::sysinfo) };
             if return_code == FALSE {
@@ -102,18 +94,27 @@ mod platform {
                 info!("Total accessible physical memory: {} MiB", total_accessible_bytes / 1024 / 1024);
                 info!("Total free physical memory: {} MiB", total_free_bytes / 1024 / 1024);
                 info!("Total available physical memory: {} MiB", available_mem_in_kb / 1024);
-                statsd_client.count(&TOTAL_BYTES, super::value_or_max(total_accessible_bytes))
-                    .expect(FATAL_ERROR);
-                statsd_client.count(&FREE_BYTES, super::value_or_max(total_free_bytes))
-                    .expect(FATAL_ERROR);
-                statsd_client.count(&AVAILABLE_BYTES, super::value_or_max(available_mem_in_kb * 1024))
-                    .expect(FATAL_ERROR);
+                Ok(vec![
+                    Measurement::new(&TOTAL_BYTES, total_accessible_bytes as f64, MetricKind::Gauge, Unit::Bytes),
+                    Measurement::new(&FREE_BYTES, total_free_bytes as f64, MetricKind::Gauge, Unit::Bytes),
+                    Measurement::new(&AVAILABLE_BYTES, (available_mem_in_kb * 1024) as f64, MetricKind::Gauge, Unit::Bytes),
+                ])
             } else {
-                error!("Error getting physical memory usage: {}", Error::last_os_error());
+                let error = Error::last_os_error();
+                Err(Error::new(error.kind(), format!("Error getting physical memory usage: {}", error)))
             }
         }
     }
 
+    #[test]
+    fn sense_reports_total_free_and_available_bytes() {
+        let measurements = PhysicalMemorySensor::new().sense().unwrap();
+        let names: Vec<&str> = measurements.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, vec!["physical_memory.total_bytes", "physical_memory.free_bytes",
+                               "physical_memory.available_bytes"]);
+        assert!(measurements.iter().all(|m| m.unit == Unit::Bytes && m.value > 0.0));
+    }
+
     fn available_memory_from_meminfo() -> Result<u64> {
         let mem_info = File::open("/proc/meminfo")?;
         for line in BufReader::new(mem_info).lines() {
@@ -136,16 +137,6 @@ mod platform {
         
         let error_message = format!("Could not find match for accessible memory regex {}",
                                     AVAILABLE_MEMORY.as_str());
-        return Err(Error::new(ErrorKind::NotFound, error_message));
-    }
-}
-
-fn value_or_max(value: u64) -> i64 {
-    if value >= i64::MAX as u64 {
-        warn!("Value {} larger than max value of {}, reporting max value {} instead",
-                value, i64::MAX, i64::MAX);
-        i64::MAX
-    } else {
-        value as i64
+        Err(Error::new(ErrorKind::NotFound, error_message))
     }
 }
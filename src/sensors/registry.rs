@@ -0,0 +1,209 @@
+extern crate serde_humantime;
+extern crate serde_yaml;
+
+use super::{CpuTimeSensor, DiskIoSensor, DiskSpaceSensor, LoadAverageSensor, NetworkInterfaceSensor, PhysicalMemorySensor};
+use filter::GlobFilter;
+use Sensor;
+use serde::de::{Deserialize, DeserializeOwned, Deserializer};
+use self::serde_humantime::De;
+use self::serde_yaml::{Mapping, Value};
+use std::ffi::OsString;
+use std::io::{Error, ErrorKind, Result};
+use std::time::Duration;
+
+/// One entry of the `sensors` section of the agent configuration
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SensorConfig {
+    #[serde(rename = "type")]
+    pub sensor_type: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    // Overrides the agent's update interval for this sensor
+    #[serde(default, deserialize_with = "deserialize_interval")]
+    pub interval: Option<Duration>,
+    // Settings specific to the sensor type, checked when the sensor is built
+    #[serde(default = "default_options")]
+    pub options: Value,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_options() -> Value {
+    Value::Null
+}
+
+fn deserialize_interval<'de, D>(deserializer: D) -> ::std::result::Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    De::<Option<Duration>>::deserialize(deserializer).map(De::into_inner)
+}
+
+/// A sensor built from configuration, along with a name that tells it apart from other sensors
+/// of the same type
+pub struct ConfiguredSensor {
+    pub name: String,
+    pub sensor: Box<dyn Sensor>,
+}
+
+struct SensorType {
+    name: &'static str,
+    build: fn(Value) -> Result<ConfiguredSensor>,
+}
+
+static SENSOR_TYPES: &[SensorType] = &[
+    SensorType { name: "disk_space", build: build_disk_space_sensor },
+    SensorType { name: "physical_memory", build: build_physical_memory_sensor },
+    SensorType { name: "cpu_time", build: build_cpu_time_sensor },
+    SensorType { name: "load_average", build: build_load_average_sensor },
+    SensorType { name: "network_interfaces", build: build_network_interfaces_sensor },
+    SensorType { name: "disk_io", build: build_disk_io_sensor },
+];
+
+/// Builds the sensor described by a configuration entry, failing if the type isn't one we know
+/// about or its options don't make sense for that type
+pub fn build_sensor(config: &SensorConfig) -> Result<ConfiguredSensor> {
+    match SENSOR_TYPES.iter().find(|sensor_type| sensor_type.name == config.sensor_type) {
+        Some(sensor_type) => (sensor_type.build)(config.options.clone()),
+        None => {
+            let known_types: Vec<&str> = SENSOR_TYPES.iter().map(|sensor_type| sensor_type.name).collect();
+            Err(Error::new(ErrorKind::InvalidInput,
+                           format!("Unknown sensor type '{}', expected one of: {}",
+                                   config.sensor_type, known_types.join(", "))))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DiskSpaceOptions {
+    // Any path on the disk to report on
+    path: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NoOptions {}
+
+fn build_disk_space_sensor(options: Value) -> Result<ConfiguredSensor> {
+    let options: DiskSpaceOptions = parse_options("disk_space", options)?;
+    Ok(ConfiguredSensor {
+        name: "disk_space.".to_string() + &options.path,
+        sensor: Box::new(DiskSpaceSensor::new(OsString::from(options.path))),
+    })
+}
+
+fn build_physical_memory_sensor(options: Value) -> Result<ConfiguredSensor> {
+    let _: NoOptions = parse_options("physical_memory", options)?;
+    Ok(ConfiguredSensor { name: "physical_memory".to_string(), sensor: Box::new(PhysicalMemorySensor::new()) })
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CpuTimeOptions {
+    // Reports each core as well as the total, tagged with its number
+    #[serde(default = "default_true")]
+    per_core: bool,
+    // Reports the time spent in user, system, iowait, steal and the other modes as well as busy
+    #[serde(default = "default_true")]
+    per_mode: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn build_cpu_time_sensor(options: Value) -> Result<ConfiguredSensor> {
+    let options: CpuTimeOptions = parse_options("cpu_time", options)?;
+    Ok(ConfiguredSensor {
+        name: "cpu_time".to_string(),
+        sensor: Box::new(CpuTimeSensor::new(options.per_core, options.per_mode)),
+    })
+}
+
+fn build_load_average_sensor(options: Value) -> Result<ConfiguredSensor> {
+    let _: NoOptions = parse_options("load_average", options)?;
+    Ok(ConfiguredSensor { name: "load_average".to_string(), sensor: Box::new(LoadAverageSensor::new()?) })
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NetworkInterfacesOptions {
+    // Glob patterns for the interfaces to report on, every one when empty
+    #[serde(default)]
+    include: Vec<String>,
+    // Glob patterns for interfaces not to report on, like lo or veth*
+    #[serde(default)]
+    exclude: Vec<String>,
+}
+
+fn build_network_interfaces_sensor(options: Value) -> Result<ConfiguredSensor> {
+    let options: NetworkInterfacesOptions = parse_options("network_interfaces", options)?;
+    let filter = GlobFilter::new(&options.include, &options.exclude);
+    Ok(ConfiguredSensor {
+        name: "network_interfaces".to_string(),
+        sensor: Box::new(NetworkInterfaceSensor::new(filter)?),
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DiskIoOptions {
+    // Glob patterns for the block devices to report on, every one when empty
+    #[serde(default)]
+    include: Vec<String>,
+    // Glob patterns for block devices not to report on, by default loop devices and RAM disks
+    #[serde(default = "default_disk_io_exclude")]
+    exclude: Vec<String>,
+    // Reports partitions as well as whole disks
+    #[serde(default)]
+    partitions: bool,
+}
+
+fn default_disk_io_exclude() -> Vec<String> {
+    vec!["loop*".to_string(), "ram*".to_string(), "zram*".to_string()]
+}
+
+fn build_disk_io_sensor(options: Value) -> Result<ConfiguredSensor> {
+    let options: DiskIoOptions = parse_options("disk_io", options)?;
+    let filter = GlobFilter::new(&options.include, &options.exclude);
+    Ok(ConfiguredSensor { name: "disk_io".to_string(), sensor: Box::new(DiskIoSensor::new(filter, options.partitions)?) })
+}
+
+fn parse_options<T>(sensor_type: &str, options: Value) -> Result<T>
+where
+    T: DeserializeOwned,
+{
+    // Leaving out the options entirely is the same as giving none
+    let options = if options.is_null() { Value::Mapping(Mapping::new()) } else { options };
+    serde_yaml::from_value(options).map_err(|e| {
+        Error::new(ErrorKind::InvalidInput, format!("Invalid options for sensor type '{}': {}", sensor_type, e))
+    })
+}
+
+#[test]
+fn build_sensor_rejects_unknown_type() {
+    let config: SensorConfig = serde_yaml::from_str("type: gpu_temperature").unwrap();
+    let error = build_sensor(&config).err().unwrap();
+    assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    assert_eq!(error.to_string(),
+               "Unknown sensor type 'gpu_temperature', expected one of: disk_space, physical_memory, cpu_time, load_average, network_interfaces, disk_io");
+}
+
+#[test]
+fn build_sensor_rejects_unknown_options() {
+    let config: SensorConfig = serde_yaml::from_str("type: disk_space\noptions:\n  paht: /var").unwrap();
+    assert!(build_sensor(&config).is_err());
+}
+
+#[test]
+fn build_sensor_names_disk_space_sensor_after_path() {
+    let config: SensorConfig =
+        serde_yaml::from_str("type: disk_space\ninterval: 5 minutes\noptions:\n  path: /var").unwrap();
+    assert!(config.enabled);
+    assert_eq!(config.interval, Some(Duration::from_secs(300)));
+    assert_eq!(build_sensor(&config).unwrap().name, "disk_space./var");
+}